@@ -94,3 +94,48 @@ pub fn unzip(bytes_in: &[u8], out_dir: &str) -> Result<(), ArchiveError> {
     println!("...done!");
     Ok(())
 }
+
+/// Unpack the IOmod package `bytes_in` into `out_dir`, and return the directory containing its
+/// `iomod.toml`. Packages made by `asml pack iomod` contain the IOmod's directory, while others
+/// may have the manifest at their root.
+pub fn unzip_iomod(bytes_in: &[u8], out_dir: &Path) -> Result<PathBuf, ArchiveError> {
+    let invalid =
+        |why: zip::result::ZipError| ArchiveError::new(format!("invalid package: {}", why));
+    let reader = std::io::Cursor::new(bytes_in);
+    let mut archive = zip::ZipArchive::new(reader).map_err(invalid)?;
+
+    for idx in 0..archive.len() {
+        let mut file = archive.by_index(idx).map_err(invalid)?;
+        // entries may be packed with a leading `/` or `./`, and are relative to the package
+        let name = file
+            .name()
+            .trim_start_matches("./")
+            .trim_start_matches('/')
+            .to_string();
+        if name.split('/').any(|component| component == "..") {
+            return Err(ArchiveError::new(format!("{} is not within the package", name)));
+        }
+
+        let path = out_dir.join(&name);
+        if file.is_dir() {
+            fs::create_dir_all(&path).map_err(|why| ArchiveError::new(why.to_string()))?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|why| ArchiveError::new(why.to_string()))?;
+        }
+        let mut outfile =
+            fs::File::create(&path).map_err(|why| ArchiveError::new(why.to_string()))?;
+        io::copy(&mut file, &mut outfile).map_err(|why| ArchiveError::new(why.to_string()))?;
+    }
+
+    if out_dir.join("iomod.toml").exists() {
+        return Ok(out_dir.to_path_buf());
+    }
+    fs::read_dir(out_dir)
+        .map_err(|why| ArchiveError::new(why.to_string()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.join("iomod.toml").exists())
+        .ok_or_else(|| ArchiveError::new("the package has no iomod.toml".to_string()))
+}
@@ -0,0 +1,690 @@
+//! Generate guest bindings for an IOmod from its [`IomodDescription`].
+//!
+//! The JSON Schemas describing each method are first lowered to a small model of named types and
+//! method signatures, which is then rendered by the module for each guest language. Enums with
+//! the default (externally tagged) serde representation are bound as enums; other enums, and
+//! references a recursive type makes to itself, are bound as untyped JSON values.
+
+pub mod rust;
+
+use serde_json::{Map, Value};
+
+use assemblylift_core_iomod::schema::IomodDescription;
+
+/// A reference to a type, as used by a field or a method signature
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeRef {
+    /// Any JSON value
+    Any,
+    Unit,
+    Bool,
+    Char,
+    String,
+    /// An integer, with its schema format (`int32`, `uint8`, ...) if it has one
+    Integer(Option<String>),
+    /// A floating point number, with its schema format (`float` or `double`) if it has one
+    Number(Option<String>),
+    Option(Box<TypeRef>),
+    List(Box<TypeRef>),
+    Tuple(Vec<TypeRef>),
+    /// An object with string keys and values of a single type
+    Map(Box<TypeRef>),
+    /// One of the [`TypeDef`]s of the bindings
+    Named(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    /// The name of the field as it is serialized
+    pub name: String,
+    pub ty: TypeRef,
+    /// Whether the field must be present; a field which may be omitted is an `Option`
+    pub required: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    /// The name of the variant as it is serialized
+    pub name: String,
+    pub kind: VariantKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariantKind {
+    Unit,
+    Newtype(TypeRef),
+    Tuple(Vec<TypeRef>),
+    Struct(Vec<Field>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeKind {
+    Struct(Vec<Field>),
+    Enum(Vec<Variant>),
+}
+
+/// A named type used by the methods of the IOmod
+#[derive(Clone, Debug)]
+pub struct TypeDef {
+    pub name: String,
+    pub kind: TypeKind,
+}
+
+#[derive(Clone, Debug)]
+pub struct MethodBinding {
+    pub name: String,
+    /// The input and output types of the method, or `None` if the IOmod does not describe them
+    pub signature: Option<(TypeRef, TypeRef)>,
+}
+
+/// The types and methods of an IOmod, ready to be rendered for a guest language
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    pub coordinates: String,
+    pub version: String,
+    /// Named types, each listed after the types it uses
+    pub types: Vec<TypeDef>,
+    pub methods: Vec<MethodBinding>,
+}
+
+impl Bindings {
+    pub fn from_description(description: &IomodDescription) -> Result<Self, String> {
+        let mut lowering = Lowering::default();
+        let mut methods = Vec::new();
+        for method in &description.methods {
+            let signature = match (&method.input, &method.output) {
+                (Some(input), Some(output)) => {
+                    let context = pascal_case(&method.name);
+                    let input = lowering.lower_root(input, &format!("{}Input", context))?;
+                    let output = lowering.lower_root(output, &format!("{}Output", context))?;
+                    Some((input, output))
+                }
+                _ => None,
+            };
+            methods.push(MethodBinding {
+                name: method.name.clone(),
+                signature,
+            });
+        }
+
+        Ok(Self {
+            coordinates: description.coordinates.clone(),
+            version: description.version.clone(),
+            types: lowering.types,
+            methods,
+        })
+    }
+}
+
+/// Lowers JSON Schemas to [`TypeRef`]s, collecting the named types they define along the way
+#[derive(Default)]
+struct Lowering {
+    types: Vec<TypeDef>,
+    /// The definitions of the schema being lowered, which `$ref`s point into
+    definitions: Map<String, Value>,
+    /// The definitions being lowered, to refuse recursive types
+    resolving: Vec<String>,
+}
+
+impl Lowering {
+    fn lower_root(&mut self, schema: &Value, context: &str) -> Result<TypeRef, String> {
+        self.definitions = schema
+            .get("definitions")
+            .and_then(Value::as_object)
+            .cloned()
+            .unwrap_or_default();
+        // a recursive root type also has a definition, which its references point to
+        let title = schema.get("title").and_then(Value::as_str);
+        let recursive = title.filter(|title| self.definitions.contains_key(*title));
+        if let Some(title) = recursive {
+            self.resolving.push(title.to_string());
+        }
+        let ty = self.lower(schema, context);
+        if recursive.is_some() {
+            self.resolving.pop();
+        }
+        ty
+    }
+
+    /// Lower `schema`, naming any anonymous struct it defines after `context`
+    fn lower(&mut self, schema: &Value, context: &str) -> Result<TypeRef, String> {
+        let schema = match schema {
+            Value::Object(schema) => schema,
+            Value::Bool(true) => return Ok(TypeRef::Any),
+            _ => return Err(format!("the schema of {} is not an object", context)),
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            return self.lower_reference(reference);
+        }
+        if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+            return match any_of.as_slice() {
+                [inner, null] | [null, inner] if is_null(null) => {
+                    Ok(TypeRef::Option(Box::new(self.lower(inner, context)?)))
+                }
+                _ => Ok(TypeRef::Any),
+            };
+        }
+        // a reference with a description or default of its own is wrapped in `allOf`
+        if let Some([inner]) = schema
+            .get("allOf")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+        {
+            return self.lower(inner, context);
+        }
+        if schema.contains_key("oneOf") || schema.contains_key("enum") {
+            let name = schema
+                .get("title")
+                .and_then(Value::as_str)
+                .unwrap_or(context)
+                .to_string();
+            return self.lower_enum(&Value::Object(schema.clone()), &name);
+        }
+        // a nullable type is described by a pair of types, e.g. `["string", "null"]`
+        if let Some([first, second]) = schema
+            .get("type")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+        {
+            let ty = match (first.as_str(), second.as_str()) {
+                (Some("null"), _) => second,
+                (_, Some("null")) => first,
+                _ => return Ok(TypeRef::Any),
+            };
+            let mut inner = schema.clone();
+            inner.insert("type".to_string(), ty.clone());
+            let inner = self.lower(&Value::Object(inner), context)?;
+            return Ok(TypeRef::Option(Box::new(inner)));
+        }
+
+        let format = schema
+            .get("format")
+            .and_then(Value::as_str)
+            .map(String::from);
+        let ty = match schema.get("type").and_then(Value::as_str) {
+            Some("null") => TypeRef::Unit,
+            Some("boolean") => TypeRef::Bool,
+            Some("integer") => TypeRef::Integer(format),
+            Some("number") => TypeRef::Number(format),
+            Some("string") if is_char(schema) => TypeRef::Char,
+            Some("string") => TypeRef::String,
+            Some("array") => match schema.get("items") {
+                Some(Value::Array(items)) => TypeRef::Tuple(self.lower_items(items, context)?),
+                Some(items) => {
+                    TypeRef::List(Box::new(self.lower(items, &format!("{}Item", context))?))
+                }
+                None => TypeRef::List(Box::new(TypeRef::Any)),
+            },
+            Some("object") => match schema.get("additionalProperties") {
+                _ if schema.contains_key("properties") => {
+                    let name = schema
+                        .get("title")
+                        .and_then(Value::as_str)
+                        .unwrap_or(context);
+                    let fields = self.lower_fields(schema, name)?;
+                    TypeRef::Named(self.define(name, TypeKind::Struct(fields)))
+                }
+                Some(values @ Value::Object(_)) => {
+                    TypeRef::Map(Box::new(self.lower(values, &format!("{}Value", context))?))
+                }
+                _ => TypeRef::Map(Box::new(TypeRef::Any)),
+            },
+            _ => TypeRef::Any,
+        };
+        Ok(ty)
+    }
+
+    fn lower_items(&mut self, items: &[Value], context: &str) -> Result<Vec<TypeRef>, String> {
+        items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| self.lower(item, &format!("{}{}", context, index)))
+            .collect()
+    }
+
+    /// Lower the properties of an object schema, in the order they are required
+    fn lower_fields(
+        &mut self,
+        schema: &Map<String, Value>,
+        context: &str,
+    ) -> Result<Vec<Field>, String> {
+        let properties = match schema.get("properties").and_then(Value::as_object) {
+            Some(properties) => properties,
+            None => return Ok(Vec::new()),
+        };
+        let required: Vec<&str> = schema
+            .get("required")
+            .and_then(Value::as_array)
+            .map(|required| required.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default();
+
+        let mut names: Vec<&String> = properties.keys().collect();
+        names.sort_by_key(|name| {
+            required
+                .iter()
+                .position(|required| required == name)
+                .unwrap_or(required.len())
+        });
+
+        let mut fields = Vec::new();
+        for name in names {
+            let context = format!("{}{}", context, pascal_case(name));
+            let mut ty = self.lower(&properties[name], &context)?;
+            let required = required.contains(&name.as_str());
+            if !required && !matches!(ty, TypeRef::Option(_)) {
+                ty = TypeRef::Option(Box::new(ty));
+            }
+            fields.push(Field {
+                name: name.clone(),
+                ty,
+                required,
+            });
+        }
+        Ok(fields)
+    }
+
+    fn lower_reference(&mut self, reference: &str) -> Result<TypeRef, String> {
+        let name = reference
+            .strip_prefix("#/definitions/")
+            .ok_or_else(|| format!("unsupported reference {}", reference))?;
+        let definition = self
+            .definitions
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("reference to undefined type {}", name))?;
+        // a recursive type cannot be bound without boxing it, so the reference is left untyped
+        if self.resolving.iter().any(|resolving| resolving == name) {
+            return Ok(TypeRef::Any);
+        }
+
+        self.resolving.push(name.to_string());
+        let ty = self.lower(&definition, name);
+        self.resolving.pop();
+        ty
+    }
+
+    /// Lower the enum described by `definition`. Only the default (externally tagged) serde
+    /// representation is bound; any other enum is left untyped.
+    fn lower_enum(&mut self, definition: &Value, name: &str) -> Result<TypeRef, String> {
+        match self.lower_variants(definition, name)? {
+            Some(variants) => Ok(TypeRef::Named(self.define(name, TypeKind::Enum(variants)))),
+            None => Ok(TypeRef::Any),
+        }
+    }
+
+    /// Lower the variants of an enum, or return `None` if they are not externally tagged
+    fn lower_variants(
+        &mut self,
+        definition: &Value,
+        context: &str,
+    ) -> Result<Option<Vec<Variant>>, String> {
+        let schemas = match definition.get("oneOf").and_then(Value::as_array) {
+            Some(schemas) => schemas.clone(),
+            None => vec![definition.clone()],
+        };
+
+        let mut variants = Vec::new();
+        for schema in schemas {
+            if let Some(names) = schema.get("enum").and_then(Value::as_array) {
+                variants.extend(names.iter().filter_map(Value::as_str).map(|name| Variant {
+                    name: name.to_string(),
+                    kind: VariantKind::Unit,
+                }));
+                continue;
+            }
+
+            // an externally tagged variant is an object with the variant's name as its only key
+            let (name, content) = match schema.get("properties").and_then(Value::as_object) {
+                Some(properties)
+                    if properties.len() == 1
+                        && schema.get("additionalProperties") == Some(&Value::Bool(false)) =>
+                {
+                    properties.iter().next().unwrap()
+                }
+                _ => return Ok(None),
+            };
+            let context = format!("{}{}", context, pascal_case(name));
+            let kind = match content.get("items") {
+                // the fields of a struct variant are described inline
+                _ if content.get("properties").is_some() => {
+                    VariantKind::Struct(self.lower_fields(content.as_object().unwrap(), &context)?)
+                }
+                Some(Value::Array(items)) => VariantKind::Tuple(self.lower_items(items, &context)?),
+                _ => VariantKind::Newtype(self.lower(content, &context)?),
+            };
+            variants.push(Variant {
+                name: name.clone(),
+                kind,
+            });
+        }
+        Ok(Some(variants))
+    }
+
+    /// Add a named type, returning its name. A type already defined the same way under that name
+    /// is reused, while a different type under the same name gets a numbered name of its own.
+    fn define(&mut self, name: &str, kind: TypeKind) -> String {
+        let name = pascal_case(name);
+        let mut candidate = name.clone();
+        for suffix in 2.. {
+            match self.types.iter().find(|def| def.name == candidate) {
+                Some(def) if def.kind == kind => return candidate,
+                Some(_) => candidate = format!("{}{}", name, suffix),
+                None => break,
+            }
+        }
+
+        self.types.push(TypeDef {
+            name: candidate.clone(),
+            kind,
+        });
+        candidate
+    }
+}
+
+fn is_null(schema: &Value) -> bool {
+    schema.get("type").and_then(Value::as_str) == Some("null")
+}
+
+fn is_char(schema: &Map<String, Value>) -> bool {
+    schema.get("minLength") == Some(&Value::from(1))
+        && schema.get("maxLength") == Some(&Value::from(1))
+}
+
+/// Convert a name such as `get_item` or `content-type` to `GetItem` or `ContentType`
+pub fn pascal_case(name: &str) -> String {
+    let mut pascal: String = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word[..1].to_ascii_uppercase() + &word[1..])
+        .collect();
+    if pascal.is_empty() || pascal.starts_with(|c: char| c.is_ascii_digit()) {
+        pascal.insert(0, 'T');
+    }
+    pascal
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use assemblylift_core_iomod::schema::{schema_for, MethodDescription};
+    use assemblylift_core_iomod::schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    struct GetInput {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        timeout_ms: Option<u32>,
+        range: (u64, u64),
+        #[serde(rename = "type")]
+        kind: Method,
+    }
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    struct GetOutput {
+        status: u16,
+        body: Vec<u8>,
+        redirect: Option<Redirect>,
+    }
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    struct Redirect {
+        location: String,
+    }
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    enum Method {
+        Get,
+        Post(Vec<u8>),
+        Range(u64, u64),
+        Form { fields: Vec<String> },
+    }
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    #[serde(tag = "kind")]
+    enum Tagged {
+        A { a: u8 },
+        B { b: u8 },
+    }
+
+    #[derive(Deserialize, Serialize, JsonSchema)]
+    #[schemars(crate = "assemblylift_core_iomod::schemars")]
+    struct Node {
+        value: i32,
+        children: Vec<Node>,
+    }
+
+    fn method(name: &str, input: Value, output: Value) -> MethodDescription {
+        MethodDescription {
+            name: name.to_string(),
+            input: Some(input),
+            output: Some(output),
+        }
+    }
+
+    fn bindings(methods: Vec<MethodDescription>) -> Bindings {
+        Bindings::from_description(&IomodDescription {
+            coordinates: "akkoro.std.http".to_string(),
+            version: "0.1.0".to_string(),
+            methods,
+        })
+        .unwrap()
+    }
+
+    fn type_def<'a>(bindings: &'a Bindings, name: &str) -> &'a TypeKind {
+        &bindings
+            .types
+            .iter()
+            .find(|def| def.name == name)
+            .unwrap_or_else(|| panic!("no type {}", name))
+            .kind
+    }
+
+    fn field<'a>(fields: &'a [Field], name: &str) -> &'a Field {
+        fields.iter().find(|field| field.name == name).unwrap()
+    }
+
+    fn named(name: &str) -> TypeRef {
+        TypeRef::Named(name.to_string())
+    }
+
+    fn option(ty: TypeRef) -> TypeRef {
+        TypeRef::Option(Box::new(ty))
+    }
+
+    #[test]
+    fn lowers_struct_fields() {
+        let bindings = bindings(vec![method(
+            "get",
+            schema_for::<GetInput>(),
+            schema_for::<GetOutput>(),
+        )]);
+        let signature = bindings.methods[0].signature.clone().unwrap();
+        assert_eq!(signature, (named("GetInput"), named("GetOutput")));
+
+        let fields = match type_def(&bindings, "GetInput") {
+            TypeKind::Struct(fields) => fields,
+            kind => panic!("expected a struct, got {:?}", kind),
+        };
+        let url = field(fields, "url");
+        assert_eq!((&url.ty, url.required), (&TypeRef::String, true));
+        let headers = field(fields, "headers");
+        let map = TypeRef::Map(Box::new(TypeRef::String));
+        assert_eq!((&headers.ty, headers.required), (&option(map), false));
+        let timeout = field(fields, "timeout_ms");
+        let int = TypeRef::Integer(Some("uint32".to_string()));
+        assert_eq!((&timeout.ty, timeout.required), (&option(int), false));
+        let uint64 = TypeRef::Integer(Some("uint64".to_string()));
+        assert_eq!(
+            field(fields, "range").ty,
+            TypeRef::Tuple(vec![uint64.clone(), uint64])
+        );
+        assert_eq!(field(fields, "type").ty, named("Method"));
+        // required fields come first
+        assert!(fields[..3].iter().all(|field| field.required));
+    }
+
+    #[test]
+    fn lowers_nested_types_before_their_users() {
+        let bindings = bindings(vec![method(
+            "get",
+            schema_for::<GetInput>(),
+            schema_for::<GetOutput>(),
+        )]);
+        let names: Vec<&str> = bindings.types.iter().map(|def| def.name.as_str()).collect();
+        let position = |name| names.iter().position(|n| *n == name).unwrap();
+        assert!(position("Method") < position("GetInput"));
+        assert!(position("Redirect") < position("GetOutput"));
+
+        let fields = match type_def(&bindings, "GetOutput") {
+            TypeKind::Struct(fields) => fields,
+            kind => panic!("expected a struct, got {:?}", kind),
+        };
+        assert_eq!(field(fields, "redirect").ty, option(named("Redirect")));
+        let uint8 = TypeRef::Integer(Some("uint8".to_string()));
+        assert_eq!(field(fields, "body").ty, TypeRef::List(Box::new(uint8)));
+    }
+
+    #[test]
+    fn lowers_externally_tagged_enum() {
+        let bindings = bindings(vec![method(
+            "m",
+            schema_for::<Method>(),
+            schema_for::<()>(),
+        )]);
+        let variants = match type_def(&bindings, "Method") {
+            TypeKind::Enum(variants) => variants,
+            kind => panic!("expected an enum, got {:?}", kind),
+        };
+        let kind = |name: &str| {
+            variants
+                .iter()
+                .find(|variant| variant.name == name)
+                .unwrap()
+                .kind
+                .clone()
+        };
+        let uint8 = TypeRef::Integer(Some("uint8".to_string()));
+        let uint64 = TypeRef::Integer(Some("uint64".to_string()));
+        assert_eq!(kind("Get"), VariantKind::Unit);
+        assert_eq!(
+            kind("Post"),
+            VariantKind::Newtype(TypeRef::List(Box::new(uint8)))
+        );
+        assert_eq!(
+            kind("Range"),
+            VariantKind::Tuple(vec![uint64.clone(), uint64])
+        );
+        match kind("Form") {
+            VariantKind::Struct(fields) => assert_eq!(fields[0].name, "fields"),
+            kind => panic!("expected a struct variant, got {:?}", kind),
+        }
+        assert_eq!(
+            bindings.methods[0].signature.as_ref().unwrap().1,
+            TypeRef::Unit
+        );
+    }
+
+    #[test]
+    fn other_enums_are_untyped() {
+        let bindings = bindings(vec![method(
+            "m",
+            schema_for::<Tagged>(),
+            schema_for::<()>(),
+        )]);
+        assert_eq!(
+            bindings.methods[0].signature.as_ref().unwrap().0,
+            TypeRef::Any
+        );
+        assert!(bindings.types.is_empty());
+    }
+
+    #[test]
+    fn recursive_references_are_untyped() {
+        let bindings = bindings(vec![method(
+            "m",
+            schema_for::<Node>(),
+            schema_for::<Node>(),
+        )]);
+        assert_eq!(bindings.types.len(), 1);
+        let fields = match type_def(&bindings, "Node") {
+            TypeKind::Struct(fields) => fields,
+            kind => panic!("expected a struct, got {:?}", kind),
+        };
+        assert_eq!(
+            field(fields, "children").ty,
+            TypeRef::List(Box::new(TypeRef::Any))
+        );
+    }
+
+    #[test]
+    fn undescribed_methods_have_no_signature() {
+        let bindings = bindings(vec![MethodDescription {
+            name: "raw".to_string(),
+            input: None,
+            output: None,
+        }]);
+        assert!(bindings.methods[0].signature.is_none());
+    }
+
+    #[test]
+    fn anonymous_types_are_named_by_context() {
+        let input = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "inner": {
+                    "type": "object",
+                    "properties": { "a": { "type": ["string", "null"] } }
+                }
+            },
+            "required": ["inner"]
+        });
+        let output = serde_json::json!({
+            "type": "object",
+            "properties": { "b": { "type": "boolean" } }
+        });
+        let bindings = bindings(vec![method("get_item", input, output)]);
+        let signature = bindings.methods[0].signature.clone().unwrap();
+        assert_eq!(signature, (named("GetItemInput"), named("GetItemOutput")));
+        let fields = match type_def(&bindings, "GetItemInputInner") {
+            TypeKind::Struct(fields) => fields,
+            kind => panic!("expected a struct, got {:?}", kind),
+        };
+        assert_eq!(fields[0].ty, option(TypeRef::String));
+    }
+
+    #[test]
+    fn different_types_with_the_same_name_are_numbered() {
+        let first = serde_json::json!({
+            "title": "Item", "type": "object", "properties": { "a": { "type": "string" } }
+        });
+        let second = serde_json::json!({
+            "title": "Item", "type": "object", "properties": { "b": { "type": "string" } }
+        });
+        let bindings = bindings(vec![
+            method("one", first.clone(), first),
+            method("two", second.clone(), second),
+        ]);
+        let names: Vec<&str> = bindings.types.iter().map(|def| def.name.as_str()).collect();
+        assert_eq!(names, vec!["Item", "Item2"]);
+    }
+
+    #[test]
+    fn pascal_case_names() {
+        assert_eq!(pascal_case("get_item"), "GetItem");
+        assert_eq!(pascal_case("content-type"), "ContentType");
+        assert_eq!(pascal_case("Already"), "Already");
+        assert_eq!(pascal_case("2fa"), "T2fa");
+        assert_eq!(pascal_case("__"), "T");
+    }
+}
@@ -0,0 +1,365 @@
+//! Render [`Bindings`] as a Rust guest crate, using the `iomod!` and `call!` macros of
+//! `assemblylift-core-iomod-guest`
+
+use std::fmt::Write;
+
+use handlebars::to_json;
+use serde_json::value::{Map, Value as Json};
+
+use crate::bindgen::{Bindings, Field, TypeDef, TypeKind, TypeRef, VariantKind};
+
+static KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+    "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
+
+/// The template data for `RUST_GUEST_DOCUMENTS`
+pub fn template_data(bindings: &Bindings) -> Result<Map<String, Json>, String> {
+    let segments: Vec<&str> = bindings.coordinates.split('.').collect();
+    if segments.len() != 3 || !segments.iter().all(|segment| is_plain_identifier(segment)) {
+        return Err(format!(
+            "coordinates {} cannot be used with the iomod! macro",
+            bindings.coordinates
+        ));
+    }
+
+    let mut calls = String::new();
+    for method in &bindings.methods {
+        if !is_plain_identifier(&method.name) {
+            return Err(format!(
+                "method {} is not a valid Rust identifier",
+                method.name
+            ));
+        }
+        match &method.signature {
+            Some((input, output)) => writeln!(
+                calls,
+                "call!({}, {} => {});",
+                method.name,
+                rust_type(input),
+                rust_type(output)
+            ),
+            None => writeln!(
+                calls,
+                "// `{}` does not describe its input and output\ncall!({}, {} => {});",
+                method.name,
+                method.name,
+                rust_type(&TypeRef::Any),
+                rust_type(&TypeRef::Any)
+            ),
+        }
+        .unwrap();
+    }
+
+    let types: Vec<String> = bindings.types.iter().map(render_type).collect();
+
+    let mut data = Map::new();
+    data.insert(
+        "crate_name".to_string(),
+        to_json(format!("{}-guest", segments.join("-"))),
+    );
+    data.insert("coordinates".to_string(), to_json(&bindings.coordinates));
+    data.insert("version".to_string(), to_json(&bindings.version));
+    data.insert("types".to_string(), to_json(types.join("\n")));
+    data.insert("calls".to_string(), to_json(calls));
+    Ok(data)
+}
+
+fn render_type(def: &TypeDef) -> String {
+    let mut out = String::from("#[derive(Clone, Debug, Deserialize, Serialize)]\n");
+    match &def.kind {
+        TypeKind::Struct(fields) => {
+            writeln!(out, "pub struct {} {{", def.name).unwrap();
+            render_fields(&mut out, fields, "    ", "pub ");
+            out.push_str("}\n");
+        }
+        TypeKind::Enum(variants) => {
+            writeln!(out, "pub enum {} {{", def.name).unwrap();
+            for variant in variants {
+                let name = identifier(&variant.name);
+                if name.trim_start_matches("r#") != variant.name {
+                    writeln!(out, "    #[serde(rename = \"{}\")]", variant.name).unwrap();
+                }
+                match &variant.kind {
+                    VariantKind::Unit => writeln!(out, "    {},", name),
+                    VariantKind::Newtype(ty) => writeln!(out, "    {}({}),", name, rust_type(ty)),
+                    VariantKind::Tuple(types) => {
+                        let types: Vec<String> = types.iter().map(rust_type).collect();
+                        writeln!(out, "    {}({}),", name, types.join(", "))
+                    }
+                    VariantKind::Struct(fields) => {
+                        writeln!(out, "    {} {{", name).unwrap();
+                        render_fields(&mut out, fields, "        ", "");
+                        writeln!(out, "    }},")
+                    }
+                }
+                .unwrap();
+            }
+            out.push_str("}\n");
+        }
+    }
+    out
+}
+
+fn render_fields(out: &mut String, fields: &[Field], indent: &str, visibility: &str) {
+    for field in fields {
+        let name = identifier(&field.name);
+        if name.trim_start_matches("r#") != field.name {
+            writeln!(out, "{}#[serde(rename = \"{}\")]", indent, field.name).unwrap();
+        }
+        // the IOmod may not accept null for a field it defaults, so an absent field is omitted
+        if !field.required {
+            writeln!(
+                out,
+                "{}#[serde(default, skip_serializing_if = \"Option::is_none\")]",
+                indent
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}{}{}: {},",
+            indent,
+            visibility,
+            name,
+            rust_type(&field.ty)
+        )
+        .unwrap();
+    }
+}
+
+fn rust_type(ty: &TypeRef) -> String {
+    match ty {
+        TypeRef::Any => "serde_json::Value".to_string(),
+        TypeRef::Unit => "()".to_string(),
+        TypeRef::Bool => "bool".to_string(),
+        TypeRef::Char => "char".to_string(),
+        TypeRef::String => "String".to_string(),
+        TypeRef::Integer(format) => match format.as_deref() {
+            Some("int8") => "i8",
+            Some("int16") => "i16",
+            Some("int32") => "i32",
+            Some("int128") => "i128",
+            Some("uint8") => "u8",
+            Some("uint16") => "u16",
+            Some("uint32") => "u32",
+            Some("uint64") => "u64",
+            Some("uint128") => "u128",
+            _ => "i64",
+        }
+        .to_string(),
+        TypeRef::Number(format) => match format.as_deref() {
+            Some("float") => "f32",
+            _ => "f64",
+        }
+        .to_string(),
+        TypeRef::Option(inner) => format!("Option<{}>", rust_type(inner)),
+        TypeRef::List(inner) => format!("Vec<{}>", rust_type(inner)),
+        TypeRef::Tuple(types) if types.len() == 1 => format!("({},)", rust_type(&types[0])),
+        TypeRef::Tuple(types) => {
+            let types: Vec<String> = types.iter().map(rust_type).collect();
+            format!("({})", types.join(", "))
+        }
+        TypeRef::Map(inner) => {
+            format!("std::collections::HashMap<String, {}>", rust_type(inner))
+        }
+        TypeRef::Named(name) => name.clone(),
+    }
+}
+
+/// A Rust identifier for a serialized name; keywords become raw identifiers, and any character
+/// which cannot appear in an identifier is replaced by an underscore
+fn identifier(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c,
+            false => '_',
+        })
+        .collect();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    match ident.as_str() {
+        "_" | "crate" | "self" | "Self" | "super" => ident.push('_'),
+        keyword if KEYWORDS.contains(&keyword) => ident.insert_str(0, "r#"),
+        _ => {}
+    }
+    ident
+}
+
+/// Whether `name` can be used as-is where a macro stringifies the identifier it is given
+fn is_plain_identifier(name: &str) -> bool {
+    identifier(name) == name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bindgen::{MethodBinding, Variant};
+
+    use super::*;
+
+    fn field(name: &str, ty: TypeRef, required: bool) -> Field {
+        Field {
+            name: name.to_string(),
+            ty,
+            required,
+        }
+    }
+
+    fn bindings(types: Vec<TypeDef>, methods: Vec<MethodBinding>) -> Bindings {
+        Bindings {
+            coordinates: "akkoro.std.http".to_string(),
+            version: "0.1.0".to_string(),
+            types,
+            methods,
+        }
+    }
+
+    fn rendered(bindings: &Bindings, key: &str) -> String {
+        template_data(bindings).unwrap()[key]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn renders_crate_metadata() {
+        let data = template_data(&bindings(Vec::new(), Vec::new())).unwrap();
+        assert_eq!(data["crate_name"], "akkoro-std-http-guest");
+        assert_eq!(data["coordinates"], "akkoro.std.http");
+        assert_eq!(data["version"], "0.1.0");
+    }
+
+    #[test]
+    fn renders_calls() {
+        let methods = vec![
+            MethodBinding {
+                name: "get".to_string(),
+                signature: Some((
+                    TypeRef::Named("GetInput".to_string()),
+                    TypeRef::Option(Box::new(TypeRef::String)),
+                )),
+            },
+            MethodBinding {
+                name: "raw".to_string(),
+                signature: None,
+            },
+        ];
+        let calls = rendered(&bindings(Vec::new(), methods), "calls");
+        assert_eq!(
+            calls,
+            "call!(get, GetInput => Option<String>);\n\
+             // `raw` does not describe its input and output\n\
+             call!(raw, serde_json::Value => serde_json::Value);\n"
+        );
+    }
+
+    #[test]
+    fn renders_struct() {
+        let def = TypeDef {
+            name: "GetInput".to_string(),
+            kind: TypeKind::Struct(vec![
+                field("url", TypeRef::String, true),
+                field("type", TypeRef::Char, true),
+                field("content-type", TypeRef::Tuple(vec![TypeRef::Bool]), true),
+                field(
+                    "timeout_ms",
+                    TypeRef::Option(Box::new(TypeRef::Integer(Some("uint32".to_string())))),
+                    false,
+                ),
+                field(
+                    "headers",
+                    TypeRef::Map(Box::new(TypeRef::Number(Some("float".to_string())))),
+                    true,
+                ),
+            ]),
+        };
+        let types = rendered(&bindings(vec![def], Vec::new()), "types");
+        assert_eq!(
+            types,
+            "#[derive(Clone, Debug, Deserialize, Serialize)]\n\
+             pub struct GetInput {\n    \
+                 pub url: String,\n    \
+                 pub r#type: char,\n    \
+                 #[serde(rename = \"content-type\")]\n    \
+                 pub content_type: (bool,),\n    \
+                 #[serde(default, skip_serializing_if = \"Option::is_none\")]\n    \
+                 pub timeout_ms: Option<u32>,\n    \
+                 pub headers: std::collections::HashMap<String, f32>,\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn renders_enum() {
+        let variant = |name: &str, kind| Variant {
+            name: name.to_string(),
+            kind,
+        };
+        let def = TypeDef {
+            name: "Method".to_string(),
+            kind: TypeKind::Enum(vec![
+                variant("Get", VariantKind::Unit),
+                variant("post-form", VariantKind::Newtype(TypeRef::Any)),
+                variant(
+                    "Range",
+                    VariantKind::Tuple(vec![TypeRef::Integer(None), TypeRef::Unit]),
+                ),
+                variant(
+                    "Form",
+                    VariantKind::Struct(vec![field(
+                        "fields",
+                        TypeRef::Option(Box::new(TypeRef::List(Box::new(TypeRef::String)))),
+                        false,
+                    )]),
+                ),
+            ]),
+        };
+        let types = rendered(&bindings(vec![def], Vec::new()), "types");
+        assert_eq!(
+            types,
+            "#[derive(Clone, Debug, Deserialize, Serialize)]\n\
+             pub enum Method {\n    \
+                 Get,\n    \
+                 #[serde(rename = \"post-form\")]\n    \
+                 post_form(serde_json::Value),\n    \
+                 Range(i64, ()),\n    \
+                 Form {\n        \
+                     #[serde(default, skip_serializing_if = \"Option::is_none\")]\n        \
+                     fields: Option<Vec<String>>,\n    \
+                 },\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(identifier("url"), "url");
+        assert_eq!(identifier("type"), "r#type");
+        assert_eq!(identifier("self"), "self_");
+        assert_eq!(identifier("_"), "__");
+        assert_eq!(identifier("x-request-id"), "x_request_id");
+        assert_eq!(identifier("2xx"), "_2xx");
+        assert_eq!(identifier(""), "__");
+    }
+
+    #[test]
+    fn rejects_names_the_macros_cannot_use() {
+        let mut bindings = bindings(Vec::new(), Vec::new());
+        bindings.coordinates = "akkoro.std-lib.http".to_string();
+        assert!(template_data(&bindings).is_err());
+        bindings.coordinates = "akkoro.http".to_string();
+        assert!(template_data(&bindings).is_err());
+
+        bindings.coordinates = "akkoro.std.http".to_string();
+        bindings.methods.push(MethodBinding {
+            name: "type".to_string(),
+            signature: None,
+        });
+        assert!(template_data(&bindings).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ArgMatches;
+use tokio::sync::mpsc;
+
+use assemblylift_core_iomod::package::{IomodManifest, ProcessKind};
+use assemblylift_core_iomod::registry::{self, RegistryChannelMessage, RegistryConfig};
+use assemblylift_core_iomod::schema::IomodDescription;
+use assemblylift_core_iomod::supervisor::{IomodProcess, IomodSupervisor};
+use assemblylift_core_iomod::transport::RegistryAddress;
+
+use crate::archive;
+use crate::bindgen::{self, Bindings};
+use crate::templates::iomod::RUST_GUEST_DOCUMENTS;
+use crate::templates::write_documents;
+
+/// How long a described IOmod has to start and register
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub fn command(matches: Option<&ArgMatches>) {
+    let matches = match matches {
+        Some(matches) => matches,
+        _ => panic!("could not get matches for iomod command"),
+    };
+
+    match matches.subcommand() {
+        ("describe", matches) => command_describe(matches),
+        ("bindgen", matches) => command_bindgen(matches),
+        _ => println!("missing subcommand. try `asml iomod help` for options."),
+    }
+}
+
+fn command_describe(matches: Option<&ArgMatches>) {
+    let matches = match matches {
+        Some(matches) => matches,
+        _ => panic!("could not get matches for describe command"),
+    };
+
+    let package_path = matches.value_of("package").unwrap(); // unwrap: this arg is required
+
+    match describe(Path::new(package_path)) {
+        Ok(description) => println!("{}", description.to_json()),
+        Err(why) => panic!("could not describe IOmod {}: {}", package_path, why),
+    }
+}
+
+fn command_bindgen(matches: Option<&ArgMatches>) {
+    let matches = match matches {
+        Some(matches) => matches,
+        _ => panic!("could not get matches for bindgen command"),
+    };
+
+    let input = matches.value_of("description").unwrap(); // unwrap: this arg is required
+    let language = matches.value_of("language").unwrap_or("rust");
+
+    // the description is either read from the package itself, or from the output of `describe`
+    let description = match input.ends_with(".iomod") {
+        true => describe(Path::new(input)),
+        false => fs::read_to_string(input)
+            .map_err(|why| why.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|why| why.to_string())),
+    };
+    let description = match description {
+        Ok(description) => description,
+        Err(why) => panic!("could not describe IOmod {}: {}", input, why),
+    };
+    let bindings = match Bindings::from_description(&description) {
+        Ok(bindings) => bindings,
+        Err(why) => panic!(
+            "could not generate bindings for {}: {}",
+            description.coordinates, why
+        ),
+    };
+
+    match language {
+        "rust" => {
+            let data = &mut match bindgen::rust::template_data(&bindings) {
+                Ok(data) => data,
+                Err(why) => panic!(
+                    "could not generate Rust bindings for {}: {}",
+                    description.coordinates, why
+                ),
+            };
+            let out_dir = match matches.value_of("out") {
+                Some(out_dir) => PathBuf::from(out_dir),
+                None => PathBuf::from(data["crate_name"].as_str().unwrap()),
+            };
+            write_documents(&out_dir, (*RUST_GUEST_DOCUMENTS).clone().as_ref(), data);
+        }
+        lang => panic!("bindings for `{}` are not supported", lang),
+    }
+}
+
+/// Describe the IOmod package at `package_path`, which is unpacked and run from a directory of
+/// its own that is removed afterwards
+fn describe(package_path: &Path) -> Result<IomodDescription, String> {
+    let package_dir = std::env::temp_dir().join(format!("asml-iomod-{}", std::process::id()));
+    let description = describe_package(package_path, &package_dir);
+    let _ = fs::remove_dir_all(&package_dir);
+    description
+}
+
+/// Unpack the IOmod package at `package_path` into `package_dir`, and ask the IOmod to describe
+/// itself once it has registered with a registry of its own
+fn describe_package(package_path: &Path, package_dir: &Path) -> Result<IomodDescription, String> {
+    let package_bytes = fs::read(package_path).map_err(|why| why.to_string())?;
+    let iomod_dir =
+        archive::unzip_iomod(&package_bytes, package_dir).map_err(|why| why.to_string())?;
+
+    let manifest_path = iomod_dir.join("iomod.toml");
+    let manifest = IomodManifest::load(&manifest_path).map_err(|why| why.to_string())?;
+    manifest.validate().map_err(|why| why.to_string())?;
+    if manifest.process.kind == ProcessKind::Wasm {
+        return Err("WASM IOmods do not describe their methods".to_string());
+    }
+    let entrypoint = iomod_dir.join(&manifest.process.entrypoint);
+    fs::set_permissions(&entrypoint, fs::Permissions::from_mode(0o755))
+        .map_err(|why| format!("could not make {:?} executable: {}", entrypoint, why))?;
+
+    // the registry listens on a socket only this IOmod knows of, so no token is needed
+    let socket_path = package_dir.join("registry.sock");
+    let config = RegistryConfig {
+        address: RegistryAddress::Unix(socket_path.clone()),
+        token: None,
+        declared_coordinates: None,
+        registration_grace: DESCRIBE_TIMEOUT,
+    };
+    let (registry_tx, registry_rx) = mpsc::channel(1);
+    registry::spawn_registry_with(registry_rx, config).map_err(|why| why.to_string())?;
+
+    let mut process = IomodProcess::from_manifest(&manifest, &iomod_dir);
+    process.environment.insert(
+        "ASML_REGISTRY_ADDRESS".to_string(),
+        format!("unix:{}", socket_path.display()),
+    );
+    // the IOmod is stopped when the supervisor is dropped
+    let _supervisor = IomodSupervisor::spawn(vec![process]);
+
+    let (responder, mut responses) = mpsc::channel(1);
+    registry_tx
+        .blocking_send(RegistryChannelMessage {
+            iomod_coords: manifest.iomod.coordinates.clone(),
+            method_name: String::new(),
+            version: None,
+            payload_type: "IOMOD_DESCRIBE",
+            payload: Vec::new(),
+            error: None,
+            responder: Some(responder),
+        })
+        .map_err(|why| why.to_string())?;
+    let response = responses
+        .blocking_recv()
+        .ok_or_else(|| "the registry exited".to_string())?;
+
+    let description: IomodDescription = match response.error {
+        Some(error) => return Err(error.to_string()),
+        None => serde_json::from_slice(&response.payload).map_err(|why| why.to_string())?,
+    };
+    check_declared_methods(&manifest, &description)?;
+    Ok(description)
+}
+
+/// Check that the IOmod describes exactly the methods its manifest declares, if it declares any
+fn check_declared_methods(
+    manifest: &IomodManifest,
+    description: &IomodDescription,
+) -> Result<(), String> {
+    let declared = match &manifest.iomod.methods {
+        Some(declared) => declared,
+        None => return Ok(()),
+    };
+    let described = description
+        .methods
+        .iter()
+        .map(|method| &method.name)
+        .collect::<Vec<_>>();
+    let undescribed = declared
+        .iter()
+        .filter(|method| !described.contains(method))
+        .cloned()
+        .collect::<Vec<_>>();
+    let undeclared = described
+        .iter()
+        .filter(|method| !declared.contains(method))
+        .map(|method| method.to_string())
+        .collect::<Vec<_>>();
+    if !undescribed.is_empty() {
+        return Err(format!(
+            "methods {} are declared in iomod.toml, but not described by the IOmod",
+            undescribed.join(", ")
+        ));
+    }
+    if !undeclared.is_empty() {
+        return Err(format!(
+            "methods {} are described by the IOmod, but not declared in iomod.toml",
+            undeclared.join(", ")
+        ));
+    }
+    Ok(())
+}
@@ -2,6 +2,7 @@ pub mod bind;
 pub mod burn;
 pub mod cast;
 pub mod init;
+pub mod iomod;
 pub mod make;
 pub mod r#move;
 pub mod nuke;
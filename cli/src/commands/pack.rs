@@ -1,6 +1,6 @@
 use clap::ArgMatches;
 
-use assemblylift_core_iomod::package::IomodManifest;
+use assemblylift_core_iomod::package::{self, IomodManifest};
 
 use crate::archive;
 
@@ -29,10 +29,16 @@ fn command_iomod(matches: Option<&ArgMatches>) {
     let mut manifest_path = cwd.clone();
     manifest_path.push("iomod.toml");
 
-    let manifest = IomodManifest::read(&manifest_path).expect(&format!(
-        "could not read iomod manifest from {:?}",
-        manifest_path
-    ));
+    let manifest = match IomodManifest::load(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(why) => panic!("could not read {:?}: {}", manifest_path, why),
+    };
+    if let Err(why) = manifest.validate() {
+        panic!("{}", why);
+    }
+    if let Err(why) = manifest.check_core_version(package::CORE_VERSION) {
+        println!("WARNING: {}", why);
+    }
 
     let entrypoint = manifest.process.entrypoint;
     let mut binary_path = cwd.clone();
@@ -41,6 +47,12 @@ fn command_iomod(matches: Option<&ArgMatches>) {
     // verify that the entrypoint exists before we pack it
     std::fs::metadata(binary_path.clone())
         .expect(&format!("could not stat {:?}", binary_path.clone()));
+    if let Some(working_dir) = manifest.process.working_dir {
+        let working_dir = cwd.join(working_dir);
+        if !working_dir.is_dir() {
+            panic!("process.working_dir {:?} is not a directory", working_dir);
+        }
+    }
 
     let out_path = matches.value_of("out").unwrap(); // unwrap: this arg is required
 
@@ -2,9 +2,10 @@ extern crate serde_json;
 
 use clap::{App, AppSettings, Arg, crate_version};
 
-use crate::commands::{bind, burn, cast, init, make, nuke, pack, push, r#move, user};
+use crate::commands::{bind, burn, cast, init, iomod, make, nuke, pack, push, r#move, user};
 
 mod archive;
+mod bindgen;
 mod commands;
 mod projectfs;
 mod providers;
@@ -91,6 +92,40 @@ fn main() {
                         ),
                 ),
         )
+        .subcommand(
+            App::new("iomod")
+                .about("Inspect IOmod packages")
+                .subcommand(
+                    App::new("describe")
+                        .about("Print the methods of a packaged IOmod and their input/output schemas")
+                        .arg(
+                            Arg::with_name("package")
+                                .required(true)
+                                .takes_value(true)
+                        ),
+                )
+                .subcommand(
+                    App::new("bindgen")
+                        .about("Generate a guest crate for an IOmod package or the output of `asml iomod describe`")
+                        .arg(
+                            Arg::with_name("description")
+                                .required(true)
+                                .takes_value(true)
+                        )
+                        .arg(
+                            Arg::with_name("out")
+                                .short("o")
+                                .takes_value(true)
+                        )
+                        .arg(
+                            Arg::with_name("language")
+                                .short("l")
+                                .long("lang")
+                                .default_value("rust")
+                                .takes_value(true)
+                        ),
+                ),
+        )
         .subcommand(
             App::new("push")
                 .about("Push artifacts to a registry")
@@ -129,6 +164,7 @@ fn main() {
 
     match matches.subcommand() {
         ("init", matches) => init::command(matches),
+        ("iomod", matches) => iomod::command(matches),
         ("cast", matches) => cast::command(matches),
         ("bind", matches) => bind::command(matches),
         ("burn", matches) => burn::command(matches),
@@ -379,13 +379,30 @@ impl Castable for LambdaFunction {
                     None => None,
                 };
 
-                let environment: StringMap<String> = function
+                let mut environment: StringMap<String> = function
                     .environment
                     .clone()
                     .unwrap_or(Rc::new(StringMap::<String>::new()))
                     .iter()
                     .map(|e| (format!("__ASML_{}", e.0.clone()), e.1.clone()))
                     .collect();
+                if let Some(timeouts) = ctx.iomod_timeouts(&service) {
+                    environment.insert("ASML_IOMOD_TIMEOUTS".into(), timeouts);
+                }
+                if let Some(rates) = ctx.iomod_rate_limits(&service) {
+                    environment.insert("ASML_IOMOD_RATE_LIMITS".into(), rates);
+                }
+                if let Some(calls) = function.iomod_max_calls {
+                    environment.insert("ASML_IOMOD_MAX_CALLS".into(), calls.to_string());
+                }
+                if let Some(in_flight) = function.iomod_max_in_flight {
+                    environment.insert("ASML_IOMOD_MAX_IN_FLIGHT".into(), in_flight.to_string());
+                }
+                environment.insert("ASML_IOMOD_VERSIONS".into(), ctx.iomod_versions(&service));
+                environment.insert(
+                    "ASML_IOMOD_CAPABILITIES".into(),
+                    ctx.iomod_capabilities(&service),
+                );
 
                 let ext = match function.precompile {
                     true => "wasm.bin",
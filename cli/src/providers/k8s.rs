@@ -231,7 +231,7 @@ impl Castable for KubernetesFunction {
                 let registries: Vec<ContainerRegistry> =
                     ctx.registries.iter().map(to_container_registry).collect();
 
-                let environment: Vec<ContainerEnv> = function
+                let mut environment: Vec<ContainerEnv> = function
                     .environment
                     .clone()
                     .unwrap_or(Rc::new(StringMap::<String>::new()))
@@ -241,6 +241,38 @@ impl Castable for KubernetesFunction {
                         value: e.1.clone()
                     })
                     .collect();
+                if let Some(timeouts) = ctx.iomod_timeouts(&service) {
+                    environment.push(ContainerEnv {
+                        name: "ASML_IOMOD_TIMEOUTS".into(),
+                        value: timeouts,
+                    });
+                }
+                if let Some(rates) = ctx.iomod_rate_limits(&service) {
+                    environment.push(ContainerEnv {
+                        name: "ASML_IOMOD_RATE_LIMITS".into(),
+                        value: rates,
+                    });
+                }
+                if let Some(calls) = function.iomod_max_calls {
+                    environment.push(ContainerEnv {
+                        name: "ASML_IOMOD_MAX_CALLS".into(),
+                        value: calls.to_string(),
+                    });
+                }
+                if let Some(in_flight) = function.iomod_max_in_flight {
+                    environment.push(ContainerEnv {
+                        name: "ASML_IOMOD_MAX_IN_FLIGHT".into(),
+                        value: in_flight.to_string(),
+                    });
+                }
+                environment.push(ContainerEnv {
+                    name: "ASML_IOMOD_VERSIONS".into(),
+                    value: ctx.iomod_versions(&service),
+                });
+                environment.push(ContainerEnv {
+                    name: "ASML_IOMOD_CAPABILITIES".into(),
+                    value: ctx.iomod_capabilities(&service),
+                });
 
                 let ext = match function.precompile {
                     true => "wasm.bin",
@@ -278,6 +310,8 @@ impl Castable for KubernetesFunction {
                     },
                     is_ruby: function.language == "ruby".to_string(),
                     cpu_compat_mode: function.cpu_compat_mode.clone(),
+                    timeout: function.timeout,
+                    size: function.size,
                     environment,
                 };
                 let hcl_content = hcl_tmpl.render();
@@ -450,6 +484,8 @@ pub struct FunctionTemplate {
     pub environment: Vec<ContainerEnv>,
     pub is_ruby: bool,
     pub cpu_compat_mode: String,
+    pub timeout: u16,
+    pub size: u16,
 }
 
 impl Template for FunctionTemplate {
@@ -487,6 +523,23 @@ resource random_id {{service_name}}_{{function_name}}_image {
     }
 }
 
+resource random_password {{service_name}}_{{function_name}}_registry_token {
+    length  = 32
+    special = false
+}
+
+resource kubernetes_secret {{service_name}}_{{function_name}}_registry_token {
+    provider   = kubernetes.{{project_name}}-k8s
+    depends_on = [kubernetes_namespace.{{service_name}}]
+    metadata {
+        name      = "{{function_name}}-registry-token"
+        namespace = "asml-${local.project_name}-{{service_name}}"
+    }
+    data = {
+        token = random_password.{{service_name}}_{{function_name}}_registry_token.result
+    }
+}
+
 resource docker_registry_image {{service_name}}_{{function_name}} {
     provider = docker.{{project_name}}-k8s
     {{#if registry.is_dockerhub}}name = "{{registry.options.registry_name}}/${local.{{service_name}}_{{function_name}}_image_name}:${random_id.{{service_name}}_{{function_name}}_image.hex}"{{/if}}
@@ -540,9 +593,6 @@ resource kubernetes_deployment {{function_name}} {
                     port {
                         container_port = 5543
                     }
-                    port {
-                        container_port = 13555
-                    }
                     {{#each environment}}
                     env {
                         name  = "{{this.name}}"
@@ -553,11 +603,37 @@ resource kubernetes_deployment {{function_name}} {
                         name  = "ASML_CPU_COMPAT_MODE"
                         value = "{{this.cpu_compat_mode}}"
                     }
+                    env {
+                        name  = "ASML_FUNCTION_TIMEOUT_SECONDS"
+                        value = "{{this.timeout}}"
+                    }
+                    env {
+                        name  = "ASML_FUNCTION_SIZE_MB"
+                        value = "{{this.size}}"
+                    }
+                    env {
+                        name = "ASML_REGISTRY_TOKEN"
+                        value_from {
+                            secret_key_ref {
+                                name = kubernetes_secret.{{service_name}}_{{function_name}}_registry_token.metadata[0].name
+                                key  = "token"
+                            }
+                        }
+                    }
                 }
                 {{#each iomods}}
                 container {
                     image = "{{this.image}}"
                     name  = "{{this.name}}"
+                    env {
+                        name = "ASML_REGISTRY_TOKEN"
+                        value_from {
+                            secret_key_ref {
+                                name = kubernetes_secret.{{../service_name}}_{{../function_name}}_registry_token.metadata[0].name
+                                key  = "token"
+                            }
+                        }
+                    }
                 }
                 {{/each}}
             }
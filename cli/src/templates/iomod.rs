@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+
+use crate::templates::Document;
+
+static GUEST_CARGO_TOML: &str = r#"[package]
+name = "{{crate_name}}"
+version = "{{version}}"
+edition = "2021"
+
+[dependencies]
+serde = { version = "1", features = ["derive"] }
+serde_json = "1"
+assemblylift_core_io_guest = { version = "0.4.0-alpha", package = "assemblylift-core-io-guest" }
+assemblylift_core_iomod_guest = { version = "0.4.0-alpha", package = "assemblylift-core-iomod-guest" }
+"#;
+
+static GUEST_LIB_RS: &str = r#"//! Guest bindings for the IOmod `{{coordinates}}@{{version}}`, generated by `asml iomod bindgen`
+
+use assemblylift_core_iomod_guest::{call, iomod};
+{{#if types}}use serde::{Deserialize, Serialize};
+{{/if}}
+iomod!({{coordinates}} @ "^{{version}}");
+{{#if types}}
+{{{types}}}{{/if}}
+{{{calls}}}"#;
+
+pub static RUST_GUEST_DOCUMENTS: Lazy<Arc<Vec<Document>>> = Lazy::new(|| {
+    Arc::new(Vec::from([
+        Document {
+            file_name: "Cargo.toml",
+            document: String::from(GUEST_CARGO_TOML),
+        },
+        Document {
+            file_name: "src/lib.rs",
+            document: String::from(GUEST_LIB_RS),
+        },
+    ]))
+});
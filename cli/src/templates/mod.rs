@@ -1,3 +1,4 @@
+pub mod iomod;
 pub mod project;
 
 use std::io::Write;
@@ -101,6 +101,8 @@ impl Context {
                     timeout: function.timeout_seconds.unwrap_or(5u16),
                     cpu_compat_mode: function.cpu_compat_mode.clone().unwrap_or("default".to_string()),
                     precompile: function.precompile.unwrap_or(true),
+                    iomod_max_calls: function.iomod_max_calls,
+                    iomod_max_in_flight: function.iomod_max_in_flight,
                     http: match &function.clone().http.as_ref() {
                         Some(http) => Some(Http {
                             verb: http.verb.clone(),
@@ -121,6 +123,9 @@ impl Context {
                     service_name: service.name.clone(),
                     coordinates: iomod.coordinates.clone(),
                     version: iomod.version.clone(),
+                    timeout: iomod.timeout_seconds,
+                    rate_limit: iomod.rate_limit.clone(),
+                    methods: iomod.methods.clone(),
                 });
             }
 
@@ -183,6 +188,66 @@ impl Context {
     pub fn service(&self, name: &str) -> Option<&Service> {
         self.services.iter().find(|&s| &s.name == name)
     }
+
+    /// Render the per-call timeouts of the IOmods `service_name` depends on, in the form read by
+    /// the runtime from `ASML_IOMOD_TIMEOUTS` (e.g. `akkoro.std.http=10,akkoro.aws.s3=30`)
+    pub fn iomod_timeouts(&self, service_name: &str) -> Option<String> {
+        let timeouts = self
+            .iomods
+            .iter()
+            .filter(|m| m.service_name == service_name)
+            .filter_map(|m| m.timeout.map(|t| format!("{}={}", m.coordinates, t)))
+            .join(",");
+        match timeouts.len() {
+            0 => None,
+            _ => Some(timeouts),
+        }
+    }
+
+    /// Render the call rates of the IOmods `service_name` depends on, in the form read by the
+    /// runtime from `ASML_IOMOD_RATE_LIMITS` (e.g. `akkoro.aws.dynamodb=50:100,akkoro.std.http=10`)
+    pub fn iomod_rate_limits(&self, service_name: &str) -> Option<String> {
+        let rates = self
+            .iomods
+            .iter()
+            .filter(|m| m.service_name == service_name)
+            .filter_map(|m| m.rate_limit.as_ref().map(|r| format!("{}={}", m.coordinates, r)))
+            .join(",");
+        match rates.len() {
+            0 => None,
+            _ => Some(rates),
+        }
+    }
+
+    /// Render the versions of the IOmods `service_name` depends on, in the form read by the
+    /// runtime from `ASML_IOMOD_VERSIONS` (e.g. `akkoro.std.http=0.2.1,akkoro.aws.s3=0.1.0`).
+    /// The runtime only accepts registrations from these IOmods, so this is rendered even if
+    /// there are none.
+    pub fn iomod_versions(&self, service_name: &str) -> String {
+        self.iomods
+            .iter()
+            .filter(|m| m.service_name == service_name)
+            .map(|m| format!("{}={}", m.coordinates, m.version))
+            .join(",")
+    }
+
+    /// Render the IOmod calls functions in `service_name` may make, in the form read by the
+    /// runtime from `ASML_IOMOD_CAPABILITIES`. Each entry is either the coordinates of a
+    /// dependency, allowing all of its methods, or the path of a single method if the dependency
+    /// lists its `methods` (e.g. `akkoro.std.http,akkoro.aws.s3.get_object`).
+    pub fn iomod_capabilities(&self, service_name: &str) -> String {
+        self.iomods
+            .iter()
+            .filter(|m| m.service_name == service_name)
+            .flat_map(|m| match &m.methods {
+                Some(methods) => methods
+                    .iter()
+                    .map(|method| format!("{}.{}", m.coordinates, method))
+                    .collect(),
+                None => vec![m.coordinates.clone()],
+            })
+            .join(",")
+    }
 }
 
 impl Castable for Context {
@@ -368,6 +433,8 @@ pub struct Function {
     pub timeout: u16,
     pub cpu_compat_mode: String,
     pub precompile: bool,
+    pub iomod_max_calls: Option<u32>,
+    pub iomod_max_in_flight: Option<u32>,
 }
 
 pub struct Http {
@@ -394,6 +461,9 @@ pub struct Iomod {
     pub service_name: String,
     pub coordinates: String,
     pub version: String,
+    pub timeout: Option<u16>,
+    pub rate_limit: Option<String>,
+    pub methods: Option<Vec<String>>,
 }
 
 #[derive(Serialize)]
@@ -209,6 +209,8 @@ pub mod service {
                 size_mb: None,
                 cpu_compat_mode: None,
                 precompile: None,
+                iomod_max_calls: None,
+                iomod_max_in_flight: None,
                 environment: None,
             };
             functions.push(fun);
@@ -305,6 +307,10 @@ pub mod service {
         pub size_mb: Option<u16>,
         pub cpu_compat_mode: Option<String>,
         pub precompile: Option<bool>,
+        /// The maximum number of IOmod calls a single invocation may make
+        pub iomod_max_calls: Option<u32>,
+        /// The maximum number of IOmod calls a single invocation may have in-flight at once
+        pub iomod_max_in_flight: Option<u32>,
         pub http: Rc<Option<HttpFunction>>,
         pub environment: Option<Rc<StringMap<String>>>,
     }
@@ -318,5 +324,11 @@ pub mod service {
     pub struct Dependency {
         pub version: String,
         pub coordinates: String,
+        pub timeout_seconds: Option<u16>,
+        /// The rate at which functions may call the IOmod, in calls per second with an optional
+        /// burst (e.g. `"50:100"`)
+        pub rate_limit: Option<String>,
+        /// The methods functions may call; every method is allowed if this is not set
+        pub methods: Option<Vec<String>>,
     }
 }
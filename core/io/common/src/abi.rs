@@ -0,0 +1,82 @@
+//! Error codes returned by the AssemblyLift ABI.
+//! ABI functions which return an `i32` return a non-negative value on success, and one of the
+//! negative codes below on failure. See [core-abi doc](../../../../docs/core-abi.md).
+
+use std::fmt;
+
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbiError {
+    /// An error not covered by any other code
+    Unknown = -1,
+    /// A pointer or length passed by the guest lies outside of its memory
+    InvalidPointer = -2,
+    /// A string passed by the guest is not valid UTF-8
+    InvalidUtf8 = -3,
+    /// An IOmod method path is not of the form `org.namespace.name.method`
+    MalformedMethodPath = -4,
+    /// The IOID does not refer to a call made by the invocation
+    UnknownIoid = -5,
+    /// There is no more data to page into a buffer
+    EndOfBuffer = -6,
+    /// The guest does not export a function or memory required by the host
+    MissingExport = -7,
+    /// The IOmod call has not completed yet
+    NotReady = -8,
+    /// The function is not permitted to call the IOmod method
+    PermissionDenied = -9,
+    /// The invocation has made as many IOmod calls as it is allowed
+    CallQuotaExceeded = -10,
+    /// The invocation has as many IOmod calls in-flight as it is allowed
+    InFlightLimitExceeded = -11,
+    /// Calls to the IOmod are being made faster than its rate limit allows
+    RateLimited = -12,
+}
+
+impl AbiError {
+    /// The code for this error as returned across the ABI
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Map a code returned across the ABI to an error; non-negative codes are not errors.
+    /// Negative codes which are not known to this version map to `Unknown`.
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            c if c >= 0 => None,
+            -2 => Some(AbiError::InvalidPointer),
+            -3 => Some(AbiError::InvalidUtf8),
+            -4 => Some(AbiError::MalformedMethodPath),
+            -5 => Some(AbiError::UnknownIoid),
+            -6 => Some(AbiError::EndOfBuffer),
+            -7 => Some(AbiError::MissingExport),
+            -8 => Some(AbiError::NotReady),
+            -9 => Some(AbiError::PermissionDenied),
+            -10 => Some(AbiError::CallQuotaExceeded),
+            -11 => Some(AbiError::InFlightLimitExceeded),
+            -12 => Some(AbiError::RateLimited),
+            _ => Some(AbiError::Unknown),
+        }
+    }
+}
+
+impl fmt::Display for AbiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AbiError::Unknown => write!(f, "unknown ABI error"),
+            AbiError::InvalidPointer => write!(f, "pointer is outside of guest memory"),
+            AbiError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            AbiError::MalformedMethodPath => write!(f, "malformed IOmod method path"),
+            AbiError::UnknownIoid => write!(f, "unknown IOID"),
+            AbiError::EndOfBuffer => write!(f, "end of buffer"),
+            AbiError::MissingExport => write!(f, "guest is missing a required export"),
+            AbiError::NotReady => write!(f, "call has not completed"),
+            AbiError::PermissionDenied => write!(f, "IOmod call is not permitted"),
+            AbiError::CallQuotaExceeded => write!(f, "IOmod call quota exceeded"),
+            AbiError::InFlightLimitExceeded => write!(f, "too many IOmod calls in-flight"),
+            AbiError::RateLimited => write!(f, "IOmod call rate limit exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for AbiError {}
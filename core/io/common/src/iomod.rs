@@ -0,0 +1,103 @@
+//! Status of IOmod calls, shared between the host and guests.
+//! A failed call responds with an `IoError` in place of its payload.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::abi::AbiError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoErrorKind {
+    /// An error not covered by any other kind
+    Unknown,
+    /// No IOmod is registered at the coordinates of the call
+    NotFound,
+    /// The IOmod does not provide the method called
+    MethodNotFound,
+    /// The IOmod rejected the input to the call
+    InvalidInput,
+    /// The IOmod call failed
+    Failed,
+    /// The IOmod could not be reached
+    Unavailable,
+    /// The IOmod did not respond before the timeout of the call
+    Timeout,
+    /// The response to the call could not be deserialized by the guest
+    InvalidResponse,
+    /// The function is not permitted to call the IOmod method
+    PermissionDenied,
+    /// The call was rejected by a limit on the calls the function may make, such as its call
+    /// quota or the rate limit of the IOmod
+    LimitExceeded,
+    /// The host returned an ABI error while the guest was handling the call
+    Abi,
+}
+
+impl fmt::Display for IoErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoErrorKind::Unknown => write!(f, "unknown error"),
+            IoErrorKind::NotFound => write!(f, "IOmod not found"),
+            IoErrorKind::MethodNotFound => write!(f, "IOmod method not found"),
+            IoErrorKind::InvalidInput => write!(f, "invalid input"),
+            IoErrorKind::Failed => write!(f, "IOmod call failed"),
+            IoErrorKind::Unavailable => write!(f, "IOmod unavailable"),
+            IoErrorKind::Timeout => write!(f, "IOmod call timed out"),
+            IoErrorKind::InvalidResponse => write!(f, "invalid response"),
+            IoErrorKind::PermissionDenied => write!(f, "permission denied"),
+            IoErrorKind::LimitExceeded => write!(f, "IOmod call limit exceeded"),
+            IoErrorKind::Abi => write!(f, "ABI error"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+/// The error returned by a failed IOmod call
+pub struct IoError {
+    pub kind: IoErrorKind,
+    pub message: String,
+}
+
+impl IoError {
+    pub fn new(kind: IoErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for IoError {}
+
+impl From<AbiError> for IoError {
+    fn from(err: AbiError) -> Self {
+        let kind = match err {
+            AbiError::PermissionDenied => IoErrorKind::PermissionDenied,
+            AbiError::CallQuotaExceeded
+            | AbiError::InFlightLimitExceeded
+            | AbiError::RateLimited => IoErrorKind::LimitExceeded,
+            _ => IoErrorKind::Abi,
+        };
+        IoError::new(kind, err.to_string())
+    }
+}
+
+/// A message is taken to be that of a call which failed
+impl From<String> for IoError {
+    fn from(message: String) -> Self {
+        IoError::new(IoErrorKind::Failed, message)
+    }
+}
+
+impl From<&str> for IoError {
+    fn from(message: &str) -> Self {
+        IoError::new(IoErrorKind::Failed, message)
+    }
+}
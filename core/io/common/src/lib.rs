@@ -1 +1,3 @@
+pub mod abi;
 pub mod constants;
+pub mod iomod;
@@ -6,14 +6,19 @@ use std::task::{Context, Poll, Waker};
 
 use serde::{de::DeserializeOwned, Deserialize};
 
+pub use assemblylift_core_io_common::abi::AbiError;
+pub use assemblylift_core_io_common::iomod::{IoError, IoErrorKind};
 use assemblylift_core_io_common::constants::{FUNCTION_INPUT_BUFFER_SIZE, IO_BUFFER_SIZE_BYTES};
 
 extern "C" {
     // IO
     fn __asml_abi_io_poll(id: u32) -> i32;
+    fn __asml_abi_io_status(id: u32) -> i32;
     fn __asml_abi_io_len(id: u32) -> u32;
     fn __asml_abi_io_load(id: u32) -> i32;
     fn __asml_abi_io_next() -> i32;
+    fn __asml_abi_io_free(id: u32) -> i32;
+    fn __asml_abi_io_cancel(id: u32) -> i32;
 
     // System clock
     fn __asml_abi_clock_time_get() -> u64;
@@ -40,6 +45,14 @@ fn console_log(message: String) {
     unsafe { __asml_abi_runtime_log(message.as_ptr(), message.len()) }
 }
 
+/// Map a code returned by an ABI function to an `AbiError` if it is negative
+fn abi_result(code: i32) -> Result<i32, AbiError> {
+    match AbiError::from_code(code) {
+        Some(err) => Err(err),
+        None => Ok(code),
+    }
+}
+
 /// Get the host clock time in seconds since UNIX epoch
 pub fn get_time() -> u64 {
     unsafe { __asml_abi_clock_time_get() }
@@ -50,53 +63,118 @@ pub fn get_time() -> u64 {
 /// first page of data returned by the call is loaded into `IO_BUFFER`; `read()` is expected to be called
 /// immediately to continue paging data in. Initializing another document with `new` will cause the
 /// data of that call to overwrite the existing data in `IO_BUFFER`.
+/// The document of a call which streams its response may be read before the call has completed
+/// (see `Io::try_document`). Its pages are loaded as they arrive, and `read()` fails with
+/// `WouldBlock` once it has caught up with the part of the response received so far.
+/// The host-side document is freed when the IoDocument is dropped.
 pub struct IoDocument {
+    ioid: u32,
     bytes_read: usize,
+    /// The number of bytes paged into `IO_BUFFER` so far
+    bytes_loaded: usize,
     pages_read: usize,
     length: usize,
+    /// Set once the call has completed, after which `length` is final
+    complete: bool,
 }
 
 impl IoDocument {
-    /// Create a new document for call ID `ioid`
+    /// Create a new document for call ID `ioid`.
+    /// Panics if the document cannot be loaded; see `try_new`.
     pub fn new(ioid: u32) -> Self {
-        unsafe { __asml_abi_io_load(ioid) };
-        Self {
+        match Self::try_new(ioid) {
+            Ok(doc) => doc,
+            Err(err) => panic!("could not load document for ioid={}: {}", ioid, err),
+        }
+    }
+
+    /// Create a new document for call ID `ioid`, or return the error raised by the host.
+    /// Returns `NotReady` if the call is still in-flight, and the first page of its response has
+    /// not arrived.
+    pub fn try_new(ioid: u32) -> Result<Self, AbiError> {
+        let bytes_loaded = abi_result(unsafe { __asml_abi_io_load(ioid) })? as usize;
+        let mut doc = Self {
+            ioid,
             bytes_read: 0,
+            bytes_loaded,
             pages_read: 0,
-            length: unsafe { __asml_abi_io_len(ioid) } as usize,
-        }
+            length: 0,
+            complete: false,
+        };
+        doc.refresh();
+        Ok(doc)
     }
 
-    /// Get the length of the document
+    /// Get the length of the document. While the call is in-flight, this is the length of the part
+    /// of its response received so far.
     pub fn len(&self) -> usize {
-        self.length
+        match self.complete {
+            true => self.length,
+            false => unsafe { __asml_abi_io_len(self.ioid) as usize },
+        }
+    }
+
+    /// Check whether the call failed, in which case the document is a JSON `IoError` rather than
+    /// the response. Returns `NotReady` while the call is in-flight.
+    pub fn failed(&self) -> Result<bool, AbiError> {
+        Ok(abi_result(unsafe { __asml_abi_io_status(self.ioid) })? == 1)
+    }
+
+    /// Check whether the call has completed, and update the length of the document
+    fn refresh(&mut self) {
+        if !self.complete {
+            self.complete = unsafe { __asml_abi_io_poll(self.ioid) } == 1;
+            self.length = unsafe { __asml_abi_io_len(self.ioid) } as usize;
+        }
+    }
+}
+
+impl Drop for IoDocument {
+    fn drop(&mut self) {
+        unsafe { __asml_abi_io_free(self.ioid) };
     }
 }
 
 impl std::io::Read for IoDocument {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
         let mut bytes_read = 0usize;
-        if self.bytes_read < self.length {
-            for idx in 0..std::cmp::min(self.length, buf.len()) {
-                // unsafe: bytes_read is always positive modulo IO_BUFFER_SIZE_BYTES
-                //         is always less than IO_BUFFER_SIZE_BYTES
-                buf[idx] = unsafe { IO_BUFFER[self.bytes_read % IO_BUFFER_SIZE_BYTES] };
-                bytes_read += 1;
-                self.bytes_read += 1;
-                if self.bytes_read % IO_BUFFER_SIZE_BYTES == 0 {
-                    unsafe { __asml_abi_io_next() };
-                    self.pages_read += 1;
+        while bytes_read < buf.len() {
+            if self.bytes_read == self.bytes_loaded {
+                // the loaded page has been read, so the next is paged in if there is more to read
+                self.refresh();
+                if self.complete && self.bytes_read >= self.length {
+                    break;
+                }
+                match abi_result(unsafe { __asml_abi_io_next() }) {
+                    Ok(loaded) => {
+                        self.bytes_loaded += loaded as usize;
+                        self.pages_read += 1;
+                    }
+                    Err(AbiError::EndOfBuffer) => break,
+                    // the rest of a streamed response has not arrived yet
+                    Err(AbiError::NotReady) if bytes_read > 0 => break,
+                    Err(AbiError::NotReady) => return Err(std::io::ErrorKind::WouldBlock.into()),
+                    Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::Other, err)),
                 }
             }
+            // unsafe: bytes_read is always positive modulo IO_BUFFER_SIZE_BYTES
+            //         is always less than IO_BUFFER_SIZE_BYTES
+            buf[bytes_read] = unsafe { IO_BUFFER[self.bytes_read % IO_BUFFER_SIZE_BYTES] };
+            bytes_read += 1;
+            self.bytes_read += 1;
         }
         Ok(bytes_read)
     }
 }
 
-#[derive(Clone)]
-/// A handle implementing `std::future::Future` for an in-flight IOmod call
+/// A handle implementing `std::future::Future` for an in-flight IOmod call.
+/// Resolves to the response of the call, or the `IoError` it failed with.
+/// Dropping the handle before it resolves cancels the call.
 pub struct Io<'a, R> {
     pub id: u32,
+    done: bool,
+    /// The error the host rejected the call with, if it was never invoked
+    error: Option<AbiError>,
     waker: Box<Option<Waker>>,
     _phantom: PhantomData<&'a R>,
 }
@@ -105,21 +183,60 @@ impl<'a, R: Deserialize<'a>> Io<'_, R> {
     pub fn new(id: u32) -> Self {
         Io {
             id,
+            done: false,
+            error: None,
+            waker: Box::new(None),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// A handle for a call which the host refused to invoke, e.g. because the function is not
+    /// permitted to make it. Resolves immediately to the `IoError` for `err`.
+    pub fn failed(err: AbiError) -> Self {
+        Io {
+            id: 0,
+            done: true,
+            error: Some(err),
             waker: Box::new(None),
             _phantom: PhantomData,
         }
     }
 }
 
+impl<R> Io<'_, R> {
+    /// Take the response as an `IoDocument`, which may be read while the IOmod is still streaming
+    /// it, rather than awaiting the deserialized response. Returns `NotReady` until the first page
+    /// of the response has arrived. Once the document is taken, dropping it rather than the handle
+    /// abandons the call.
+    pub fn try_document(&mut self) -> Result<IoDocument, AbiError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+        let doc = IoDocument::try_new(self.id)?;
+        self.done = true;
+        Ok(doc)
+    }
+}
+
 impl<'a, R> Future for Io<'_, R>
 where
     R: DeserializeOwned,
 {
-    type Output = R;
+    type Output = Result<R, IoError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match unsafe { __asml_abi_io_poll(self.id) } {
-            1 => Poll::Ready(read_response::<Self::Output>(self.id).unwrap()),
+        if let Some(err) = self.error {
+            return Poll::Ready(Err(err.into()));
+        }
+        match abi_result(unsafe { __asml_abi_io_poll(self.id) }) {
+            Ok(1) => {
+                self.done = true;
+                Poll::Ready(read_response::<R>(self.id))
+            }
+            Err(err) => {
+                self.done = true;
+                Poll::Ready(Err(err.into()))
+            }
             _ => {
                 self.waker = Box::new(Some(cx.waker().clone()));
                 Poll::Pending
@@ -128,18 +245,34 @@ where
     }
 }
 
-fn read_response<'a, T>(id: u32) -> Option<T>
+impl<R> Drop for Io<'_, R> {
+    fn drop(&mut self) {
+        if !self.done {
+            unsafe { __asml_abi_io_cancel(self.id) };
+        }
+    }
+}
+
+/// Read the response to call `id`, or the error it failed with
+fn read_response<T>(id: u32) -> Result<T, IoError>
 where
     T: DeserializeOwned,
 {
-    let doc = IoDocument::new(id);
+    let failed = abi_result(unsafe { __asml_abi_io_status(id) })? == 1;
+    let doc = IoDocument::try_new(id)?;
     let doc = BufReader::with_capacity(doc.len(), doc);
-    match serde_json::from_reader::<BufReader<IoDocument>, T>(doc) {
-        Ok(response) => Some(response),
-        Err(why) => {
-            console_log(format!("[ERROR] ioid={} {}", id, why.to_string()));
-            None
-        }
+    match failed {
+        true => match serde_json::from_reader::<BufReader<IoDocument>, IoError>(doc) {
+            Ok(error) => Err(error),
+            Err(why) => Err(IoError::new(IoErrorKind::InvalidResponse, why.to_string())),
+        },
+        false => match serde_json::from_reader::<BufReader<IoDocument>, T>(doc) {
+            Ok(response) => Ok(response),
+            Err(why) => {
+                console_log(format!("[ERROR] ioid={} {}", id, why));
+                Err(IoError::new(IoErrorKind::InvalidResponse, why.to_string()))
+            }
+        },
     }
 }
 
@@ -164,12 +297,24 @@ pub struct FunctionInputBuffer {
 }
 
 impl FunctionInputBuffer {
+    /// Load the first page of function input. Panics if the input cannot be loaded; see `try_new`.
     pub fn new() -> Self {
-        unsafe { __asml_abi_input_start() };
-        Self {
-            bytes_read: 0usize,
-            pages_read: 0usize,
-            length: unsafe { __asml_abi_input_length_get() as usize },
+        match Self::try_new() {
+            Ok(fib) => fib,
+            Err(err) => panic!("could not load function input: {}", err),
+        }
+    }
+
+    /// Load the first page of function input, or return the error raised by the host
+    pub fn try_new() -> Result<Self, AbiError> {
+        match abi_result(unsafe { __asml_abi_input_start() }) {
+            // empty input has no first page
+            Ok(_) | Err(AbiError::EndOfBuffer) => Ok(Self {
+                bytes_read: 0usize,
+                pages_read: 0usize,
+                length: unsafe { __asml_abi_input_length_get() as usize },
+            }),
+            Err(err) => Err(err),
         }
     }
 }
@@ -185,8 +330,11 @@ impl std::io::Read for FunctionInputBuffer {
                     unsafe { FUNCTION_INPUT_BUFFER[self.bytes_read % FUNCTION_INPUT_BUFFER_SIZE] };
                 bytes_read += 1;
                 self.bytes_read += 1;
-                if self.bytes_read % FUNCTION_INPUT_BUFFER_SIZE == 0 {
-                    unsafe { __asml_abi_input_next() };
+                if self.bytes_read % FUNCTION_INPUT_BUFFER_SIZE == 0 && self.bytes_read < self.length
+                {
+                    if let Err(err) = abi_result(unsafe { __asml_abi_input_next() }) {
+                        return Err(std::io::Error::new(std::io::ErrorKind::Other, err));
+                    }
                     self.pages_read += 1;
                 }
             }
@@ -1,12 +1,27 @@
 pub mod macros {
+    /// Declare the IOmod whose calls are bound in this module. A semver requirement may be given
+    /// with `@ "requirement"`, otherwise calls are routed using the version of the dependency in
+    /// the service manifest.
     #[macro_export]
     macro_rules! iomod {
         ($org:ident.$namespace:ident.$name:ident) => {
+            $crate::__iomod!($org.$namespace.$name, None);
+        };
+        ($org:ident.$namespace:ident.$name:ident @ $version:literal) => {
+            $crate::__iomod!($org.$namespace.$name, Some($version));
+        };
+    }
+
+    #[macro_export]
+    #[doc(hidden)]
+    macro_rules! __iomod {
+        ($org:ident.$namespace:ident.$name:ident, $version:expr) => {
             use assemblylift_core_io_guest::{Io, IO_BUFFER};
 
             static IOMOD_ORG: &'static str = std::stringify!($org);
             static IOMOD_NAMESPACE: &'static str = std::stringify!($namespace);
             static IOMOD_NAME: &'static str = std::stringify!($name);
+            static IOMOD_VERSION: Option<&'static str> = $version;
 
             extern "C" {
                 fn __asml_abi_invoke(
@@ -26,8 +41,11 @@ pub mod macros {
                 use serde_json;
 
                 let name = std::stringify!($name);
-                let method_path =
+                let mut method_path =
                     format!("{}.{}.{}.{}", IOMOD_ORG, IOMOD_NAMESPACE, IOMOD_NAME, name);
+                if let Some(version) = IOMOD_VERSION {
+                    method_path = format!("{}@{}", method_path, version);
+                }
 
                 let ioid: i32;
                 unsafe {
@@ -40,9 +58,10 @@ pub mod macros {
                     );
                 }
 
-                match ioid {
-                    -1 => panic!("unable to invoke fn {}", name),
-                    _ => Io::<$output>::new(ioid as u32),
+                // a call the host refused to invoke resolves to the error it was refused with
+                match assemblylift_core_io_guest::AbiError::from_code(ioid) {
+                    Some(err) => Io::<$output>::failed(err),
+                    None => Io::<$output>::new(ioid as u32),
                 }
             }
         };
@@ -5,17 +5,32 @@ use std::collections::HashMap;
 use std::rc::Rc;
 
 use capnp::capability::Promise;
-use capnp::{Error, ErrorKind};
+use capnp::Error;
 use futures::future::BoxFuture;
-use futures_util::TryFutureExt;
+use futures::stream::BoxStream;
+use futures::{FutureExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
 use tokio::sync::mpsc;
 
-use crate::iomod_capnp::{agent, iomod};
+pub use assemblylift_core_io_common::iomod::{IoError, IoErrorKind};
+pub use schemars;
+#[doc(hidden)]
+pub use tracing as __tracing;
+
+use crate::iomod_capnp::{
+    agent, description, error, iomod, response, response_stream, ErrorKind as CapnpErrorKind,
+};
+use crate::schema::{schema_for, IomodDescription, JsonSchema, MethodDescription};
 
 pub mod iomod_capnp;
 pub mod macros;
 pub mod package;
 pub mod registry;
+pub mod schema;
+pub mod supervisor;
+pub mod transport;
 
 pub struct CallRequest {
     pub coords: String,
@@ -23,33 +38,101 @@ pub struct CallRequest {
     pub responder: mpsc::Sender<CallResponse>,
 }
 
+/// The response to a `CallRequest`. A streamed response is sent as several `CallResponse`s, each
+/// but the last of which is `partial`; the response is the concatenation of their payloads.
 pub struct CallResponse {
     pub coords: String,
     pub payload: Vec<u8>,
+    /// Set if the call failed, in which case `payload` is empty
+    pub error: Option<IoError>,
+    /// Set if more of the response follows
+    pub partial: bool,
 }
 
 pub type CallChannel = (mpsc::Sender<CallRequest>, mpsc::Receiver<CallRequest>);
 
 pub type Call<F> = fn(Vec<u8>) -> F;
 
-pub struct CallPtr<F>
-where
-    F: std::future::Future<Output = Vec<u8>> + Send,
-{
-    call: Call<F>,
+/// A call, which produces its response as a stream of chunks
+type BoxedCall<'a> =
+    Box<dyn Fn(Vec<u8>) -> BoxStream<'a, Result<Vec<u8>, IoError>> + Send + Sync + 'a>;
+
+pub struct CallPtr<'a> {
+    call: BoxedCall<'a>,
+    /// The JSON Schemas of the input and output of a typed call
+    schemas: Option<(Value, Value)>,
 }
 
-impl<F> CallPtr<F>
-where
-    F: std::future::Future<Output = Vec<u8>> + Send,
-{
-    pub fn new(call: Call<F>) -> Self {
-        Self { call }
+impl<'a> CallPtr<'a> {
+    /// An untyped call, which takes and returns raw bytes
+    pub fn new<F>(call: Call<F>) -> Self
+    where
+        F: std::future::Future<Output = Vec<u8>> + Send + 'a,
+    {
+        Self {
+            call: Box::new(move |input| call(input).map(Ok).into_stream().boxed()),
+            schemas: None,
+        }
+    }
+
+    /// An untyped call which streams its response, so that it can be delivered before the call
+    /// completes. The chunks are written to the response in order, and the first error fails the
+    /// call.
+    pub fn streaming<C, S, E>(call: C) -> Self
+    where
+        C: Fn(Vec<u8>) -> S + Send + Sync + 'a,
+        S: Stream<Item = Result<Vec<u8>, E>> + Send + 'a,
+        E: Into<IoError>,
+    {
+        Self {
+            call: Box::new(move |input| call(input).map(|chunk| chunk.map_err(Into::into)).boxed()),
+            schemas: None,
+        }
+    }
+
+    /// A typed call, whose input is deserialized from and output serialized to JSON. An input
+    /// which cannot be deserialized fails the call with `InvalidInput`. The schemas of `I` and
+    /// `O` are generated from their `JsonSchema` implementations (see the `schema` module).
+    pub fn typed<C, F, I, O, E>(call: C) -> Self
+    where
+        C: Fn(I) -> F + Send + Sync + 'a,
+        F: std::future::Future<Output = Result<O, E>> + Send + 'a,
+        I: DeserializeOwned + JsonSchema,
+        O: Serialize + JsonSchema,
+        E: Into<IoError>,
+    {
+        Self {
+            call: Box::new(move |input| match serde_json::from_slice::<I>(&input) {
+                Ok(input) => call(input)
+                    .map(|result| {
+                        let output = result.map_err(Into::into)?;
+                        serde_json::to_vec(&output).map_err(|why| {
+                            IoError::new(
+                                IoErrorKind::Failed,
+                                format!("could not serialize output: {}", why),
+                            )
+                        })
+                    })
+                    .into_stream()
+                    .boxed(),
+                Err(why) => {
+                    let error = IoError::new(IoErrorKind::InvalidInput, why.to_string());
+                    futures::stream::once(futures::future::ready(Err(error))).boxed()
+                }
+            }),
+            schemas: Some((schema_for::<I>(), schema_for::<O>())),
+        }
     }
 }
 
 pub struct CallMap<'a> {
-    pub map: HashMap<&'a str, CallPtr<BoxFuture<'a, Vec<u8>>>>,
+    pub map: HashMap<&'a str, CallPtr<'a>>,
+}
+
+impl<'a> Default for CallMap<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> CallMap<'a> {
@@ -59,19 +142,66 @@ impl<'a> CallMap<'a> {
         }
     }
 
-    pub fn get(&self, coords: String, with_input: Vec<u8>) -> BoxFuture<'a, Vec<u8>> {
-        let call = self.map[coords.as_str()].call;
-        call(with_input)
+    /// Call the method named `coords` with `with_input`, if the map contains it. The response is
+    /// produced in chunks; a call which does not stream its response produces a single chunk.
+    pub fn get(
+        &self,
+        coords: String,
+        with_input: Vec<u8>,
+    ) -> Option<BoxStream<'a, Result<Vec<u8>, IoError>>> {
+        let call = &self.map.get(coords.as_str())?.call;
+        Some(call(with_input))
+    }
+
+    /// Describe the methods in the map, as served by the IOmod at `coordinates`
+    pub fn describe(&self, coordinates: &str, version: &str) -> IomodDescription {
+        let mut methods = self
+            .map
+            .iter()
+            .map(|(name, call)| MethodDescription {
+                name: name.to_string(),
+                input: call.schemas.as_ref().map(|(input, _)| input.clone()),
+                output: call.schemas.as_ref().map(|(_, output)| output.clone()),
+            })
+            .collect::<Vec<_>>();
+        methods.sort_by(|a, b| a.name.cmp(&b.name));
+        IomodDescription {
+            coordinates: coordinates.to_string(),
+            version: version.to_string(),
+            methods,
+        }
+    }
+}
+
+/// An IOmod implemented in Rust and served inside the runtime's process. Calls to it are made
+/// directly by the registry, rather than over RPC; see `RegistryHandle::register_in_process`.
+pub trait InProcessIomod: Send + Sync {
+    /// Call the method named `method` with `input`
+    fn call(&self, method: &str, input: Vec<u8>) -> BoxFuture<'static, Result<Vec<u8>, IoError>>;
+
+    /// Describe the methods the IOmod serves, if it can
+    fn describe(&self) -> Option<IomodDescription> {
+        None
     }
 }
 
 pub struct Iomod {
     tx: mpsc::Sender<CallRequest>,
+    description: Option<IomodDescription>,
 }
 
 impl Iomod {
     pub fn new(tx: mpsc::Sender<CallRequest>) -> Self {
-        Self { tx }
+        Self {
+            tx,
+            description: None,
+        }
+    }
+
+    /// Serve `description` to `describe` requests
+    pub fn with_description(mut self, description: IomodDescription) -> Self {
+        self.description = Some(description);
+        self
     }
 }
 
@@ -84,34 +214,114 @@ impl iomod::Server for Iomod {
         let tx = self.tx.clone();
 
         Promise::from_future(async move {
-            let coords = params.get().unwrap().get_coordinates().unwrap().to_owned();
-            let input = params.get().unwrap().get_input().unwrap();
-
-            let mut channel: (mpsc::Sender<CallResponse>, mpsc::Receiver<CallResponse>) =
-                mpsc::channel(100);
+            let params = params.get()?;
+            let coords = params.get_coordinates()?.to_owned();
+            let input = Vec::from(params.get_input()?);
 
+            // the executor holds the only sender, so the receiver closes if it drops the call
+            let (responder, mut responses) = mpsc::channel(100);
             tx.send(CallRequest {
                 coords,
-                input: Vec::from(input),
-                responder: channel.0.clone(),
+                input,
+                responder,
             })
-            .and_then(|_| async move {
-                // wait for response from executor thread
-                if let Some(response) = channel.1.recv().await {
-                    results.get().set_result(response.payload.as_slice());
+            .await
+            .map_err(|why| capnp::Error::failed(why.to_string()))?;
+
+            // wait for response from executor thread, gathering a streamed response into one
+            let mut payload = Vec::new();
+            while let Some(response) = responses.recv().await {
+                payload.extend_from_slice(&response.payload);
+                if response.partial {
+                    continue;
                 }
+                match &response.error {
+                    Some(error) => write_error(results.get().init_error(), error),
+                    None => results.get().set_result(&payload),
+                }
+                return Ok(());
+            }
 
-                Ok(())
-            })
-            .or_else(|why| async move {
-                Err(capnp::Error {
-                    kind: ErrorKind::Failed,
-                    description: why.to_string(),
-                })
+            let error = IoError::new(IoErrorKind::Failed, "IOmod executor dropped the call");
+            write_error(results.get().init_error(), &error);
+            Ok(())
+        })
+    }
+
+    fn invoke_streaming(
+        &mut self,
+        params: iomod::InvokeStreamingParams,
+        _results: iomod::InvokeStreamingResults,
+    ) -> Promise<(), Error> {
+        let tx = self.tx.clone();
+
+        Promise::from_future(async move {
+            let params = params.get()?;
+            let coords = params.get_coordinates()?.to_owned();
+            let input = Vec::from(params.get_input()?);
+            let stream: response_stream::Client = params.get_stream()?;
+
+            let (responder, mut responses) = mpsc::channel(100);
+            tx.send(CallRequest {
+                coords,
+                input,
+                responder,
             })
             .await
+            .map_err(|why| capnp::Error::failed(why.to_string()))?;
+
+            // chunks are written one at a time; if the reader has gone away the write fails, and
+            // dropping `responses` stops the call
+            while let Some(response) = responses.recv().await {
+                if response.partial {
+                    let mut write = stream.write_request();
+                    write.get().set_chunk(&response.payload);
+                    write.send().promise.await?;
+                    continue;
+                }
+                let result = match &response.error {
+                    Some(error) => Err(error),
+                    None => Ok(response.payload.as_slice()),
+                };
+                let mut end = stream.end_request();
+                write_response(end.get().init_response(), result);
+                end.send().promise.await?;
+                return Ok(());
+            }
+
+            Err(capnp::Error::failed(
+                "IOmod executor dropped the call".to_string(),
+            ))
         })
     }
+
+    fn ping(
+        &mut self,
+        _params: iomod::PingParams,
+        _results: iomod::PingResults,
+    ) -> Promise<(), Error> {
+        // the IOmod cannot serve calls once its executor has gone away
+        match self.tx.is_closed() {
+            true => Promise::err(capnp::Error::disconnected("IOmod executor has exited".into())),
+            false => Promise::ok(()),
+        }
+    }
+
+    fn describe(
+        &mut self,
+        _params: iomod::DescribeParams,
+        mut results: iomod::DescribeResults,
+    ) -> Promise<(), Error> {
+        match &self.description {
+            Some(description) => {
+                write_description(results.get().init_description(), description);
+                Promise::ok(())
+            }
+            None => Promise::err(capnp::Error::unimplemented(
+                "IOmod does not describe its methods".into(),
+            )),
+        }
+    }
 }
 
 pub struct Agent {
@@ -141,12 +351,220 @@ impl agent::Server for Agent {
                 .get()
                 .set_input(params.get().unwrap().get_input().unwrap());
 
-            let invoke_response = invoke.send().promise.await.unwrap();
-            results
-                .get()
-                .set_result(invoke_response.get().unwrap().get_result().unwrap());
+            let invoke_response = invoke.send().promise.await?;
+            let invoke_response = invoke_response.get()?;
+            results.get().set_result(invoke_response.get_result()?);
+            if invoke_response.has_error() {
+                results.get().set_error(invoke_response.get_error()?)?;
+            }
 
             Ok(())
         })
     }
 }
+
+/// Write the outcome of an IOmod call into `builder`
+pub fn write_response(mut builder: response::Builder, result: Result<&[u8], &IoError>) {
+    match result {
+        Ok(payload) => builder.set_ok(payload),
+        Err(error) => write_error(builder.init_error(), error),
+    }
+}
+
+/// Write the error an IOmod call failed with into `builder`
+pub fn write_error(mut builder: error::Builder, error: &IoError) {
+    builder.set_kind(match error.kind {
+        IoErrorKind::NotFound => CapnpErrorKind::NotFound,
+        IoErrorKind::MethodNotFound => CapnpErrorKind::MethodNotFound,
+        IoErrorKind::InvalidInput => CapnpErrorKind::InvalidInput,
+        IoErrorKind::Failed => CapnpErrorKind::Failed,
+        IoErrorKind::Unavailable => CapnpErrorKind::Unavailable,
+        IoErrorKind::Timeout => CapnpErrorKind::Timeout,
+        _ => CapnpErrorKind::Unknown,
+    });
+    builder.set_message(error.message.as_str());
+}
+
+/// Read the outcome of an IOmod call from `reader`
+pub fn read_response(reader: response::Reader) -> Result<Vec<u8>, IoError> {
+    match reader.which() {
+        Ok(response::Ok(payload)) => Ok(Vec::from(payload.map_err(malformed_response)?)),
+        Ok(response::Error(error)) => Err(read_error(error.map_err(malformed_response)?)),
+        Err(not_in_schema) => Err(IoError::new(
+            IoErrorKind::Unknown,
+            format!("malformed response: {}", not_in_schema),
+        )),
+    }
+}
+
+/// Read the error an IOmod call failed with from `reader`
+pub fn read_error(reader: error::Reader) -> IoError {
+    let kind = match reader.get_kind() {
+        Ok(CapnpErrorKind::NotFound) => IoErrorKind::NotFound,
+        Ok(CapnpErrorKind::MethodNotFound) => IoErrorKind::MethodNotFound,
+        Ok(CapnpErrorKind::InvalidInput) => IoErrorKind::InvalidInput,
+        Ok(CapnpErrorKind::Failed) => IoErrorKind::Failed,
+        Ok(CapnpErrorKind::Unavailable) => IoErrorKind::Unavailable,
+        Ok(CapnpErrorKind::Timeout) => IoErrorKind::Timeout,
+        _ => IoErrorKind::Unknown,
+    };
+    match reader.get_message() {
+        Ok(message) => IoError::new(kind, message),
+        Err(why) => malformed_response(why),
+    }
+}
+
+/// The error for a response which could not be read
+pub fn malformed_response(why: capnp::Error) -> IoError {
+    IoError::new(IoErrorKind::Unknown, format!("malformed response: {}", why))
+}
+
+/// Write `description` into `builder`. The schemas of untyped methods are written as empty text.
+pub fn write_description(mut builder: description::Builder, description: &IomodDescription) {
+    builder.set_coordinates(description.coordinates.as_str());
+    builder.set_version(description.version.as_str());
+    let mut methods = builder.init_methods(description.methods.len() as u32);
+    for (idx, method) in description.methods.iter().enumerate() {
+        let mut builder = methods.reborrow().get(idx as u32);
+        builder.set_name(method.name.as_str());
+        let schema = |schema: &Option<Value>| {
+            schema
+                .as_ref()
+                .map(Value::to_string)
+                .unwrap_or_default()
+        };
+        builder.set_input_schema(schema(&method.input).as_str());
+        builder.set_output_schema(schema(&method.output).as_str());
+    }
+}
+
+/// Read an IOmod's description from `reader`
+pub fn read_description(reader: description::Reader) -> Result<IomodDescription, capnp::Error> {
+    let schema = |schema: &str| match schema {
+        "" => Ok(None),
+        schema => serde_json::from_str(schema)
+            .map(Some)
+            .map_err(|why| capnp::Error::failed(format!("malformed schema: {}", why))),
+    };
+    let methods = reader
+        .get_methods()?
+        .iter()
+        .map(|method| {
+            Ok(MethodDescription {
+                name: method.get_name()?.to_string(),
+                input: schema(method.get_input_schema()?)?,
+                output: schema(method.get_output_schema()?)?,
+            })
+        })
+        .collect::<Result<Vec<_>, capnp::Error>>()?;
+    Ok(IomodDescription {
+        coordinates: reader.get_coordinates()?.to_string(),
+        version: reader.get_version()?.to_string(),
+        methods,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize, JsonSchema)]
+    struct AddInput {
+        a: i64,
+        b: i64,
+    }
+
+    #[derive(Serialize, JsonSchema)]
+    struct AddOutput {
+        sum: i64,
+    }
+
+    async fn echo(input: Vec<u8>) -> Vec<u8> {
+        input
+    }
+
+    fn calls() -> CallMap<'static> {
+        let mut calls = CallMap::new();
+        calls.map.insert(
+            "add",
+            CallPtr::typed(|input: AddInput| async move {
+                match input.a.checked_add(input.b) {
+                    Some(sum) => Ok(AddOutput { sum }),
+                    None => Err(IoError::new(IoErrorKind::Failed, "overflow")),
+                }
+            }),
+        );
+        calls.map.insert("echo", CallPtr::new(echo));
+        calls
+    }
+
+    fn call(method: &str, input: &str) -> Result<Vec<u8>, IoError> {
+        let chunks = calls().get(method.to_string(), input.as_bytes().to_vec());
+        let chunks = block_on(chunks.expect("no such method").collect::<Vec<_>>());
+        assert_eq!(
+            chunks.len(),
+            1,
+            "a call which does not stream responds with one chunk"
+        );
+        chunks.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn typed_call_decodes_input_and_encodes_output() {
+        let output = call("add", r#"{ "a": 1, "b": 2 }"#).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&output).unwrap(),
+            serde_json::json!({ "sum": 3 })
+        );
+    }
+
+    #[test]
+    fn typed_call_rejects_malformed_input() {
+        for input in &["", "not json", r#"{ "a": 1 }"#, r#"{ "a": "1", "b": 2 }"#] {
+            let error = call("add", input).unwrap_err();
+            assert_eq!(error.kind, IoErrorKind::InvalidInput, "input {:?}", input);
+        }
+    }
+
+    #[test]
+    fn typed_call_returns_its_error() {
+        let input = format!(r#"{{ "a": {}, "b": 1 }}"#, i64::MAX);
+        assert_eq!(
+            call("add", &input).unwrap_err(),
+            IoError::new(IoErrorKind::Failed, "overflow")
+        );
+    }
+
+    #[test]
+    fn untyped_call_passes_bytes_through() {
+        assert_eq!(call("echo", "not json").unwrap(), b"not json".to_vec());
+    }
+
+    #[test]
+    fn unknown_method() {
+        assert!(calls().get("nope".to_string(), Vec::new()).is_none());
+    }
+
+    #[test]
+    fn describe_lists_methods_with_their_schemas() {
+        let description = calls().describe("akkoro.std.math", "1.2.0");
+        assert_eq!(description.coordinates, "akkoro.std.math");
+        assert_eq!(description.version, "1.2.0");
+
+        let names: Vec<&str> = description
+            .methods
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["add", "echo"]);
+
+        let add = &description.methods[0];
+        assert_eq!(add.input, Some(schema_for::<AddInput>()));
+        assert_eq!(add.output, Some(schema_for::<AddOutput>()));
+        let echo = &description.methods[1];
+        assert_eq!((&echo.input, &echo.output), (&None, &None));
+    }
+}
@@ -1,16 +1,49 @@
-pub static CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub use crate::package::CORE_VERSION;
 pub static RUSTC_VERSION: &str = env!("RUSTC_VERSION");
 
+/// Start an IOmod, registering it with the registry at `$address`.
+/// The address may be a host, a `host:port`, or a Unix socket as `unix:<path>`; if
+/// `ASML_REGISTRY_ADDRESS` is set it is used instead, so that the IOmod connects wherever the
+/// runtime's registry is listening.
+/// The IOmod authenticates with the token in `ASML_REGISTRY_TOKEN`, which the runtime passes to
+/// the IOmods it launches.
+/// The IOmod registers with the version of the crate invoking the macro, unless one is given
+/// with `@ "version"` after its coordinates.
+///
+/// Each call is declared as `name => call`. An untyped call is a `fn(Vec<u8>) -> BoxFuture<Vec<u8>>`
+/// over the raw input and output. A typed call is declared as `name => typed call`, where `call`
+/// is an `async fn(Input) -> Result<Output, Error>`: the input and output are (de)serialized as
+/// JSON with serde, and the error is any type convertible to an `IoError` (including `String`).
+/// The input and output must also implement `schemars::JsonSchema`, from which their schemas are
+/// described; `schemars` is re-exported for IOmods which do not depend on it themselves.
+/// A call declared as `name => streaming call` returns a `Stream` of `Result<Vec<u8>, Error>`
+/// chunks, which are delivered to the function as they are produced rather than once the call
+/// completes.
+///
+/// ```text
+/// iomod!(registry_address, akkoro.std.clock => {
+///     now => typed now,
+///     raw => raw_call,
+///     download => streaming download,
+/// });
+/// ```
+///
+/// Run with `--describe`, the IOmod prints a JSON description of its methods and the schemas of
+/// their inputs and outputs (see `schema::IomodDescription`) and exits, instead of registering.
+/// The same description is served to the registry's `describe` requests.
 #[macro_export]
 macro_rules! iomod {
-    ($ip:expr, $org:ident.$ns:ident.$name:ident => $calls:tt) => {
+    ($address:expr, $org:ident.$ns:ident.$name:ident => $calls:tt) => {
+        $crate::iomod!($address, $org.$ns.$name @ env!("CARGO_PKG_VERSION") => $calls)
+    };
+    ($address:expr, $org:ident.$ns:ident.$name:ident @ $version:expr => $calls:tt) => {
         use assemblylift_core_iomod::iomod_capnp::*;
         use assemblylift_core_iomod::{
-            Call, CallChannel, CallMap, CallPtr, CallRequest, CallResponse, Iomod,
+            Call, CallChannel, CallMap, CallPtr, CallRequest, CallResponse, Iomod, IoError,
+            IoErrorKind,
         };
         use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-        use futures::{AsyncReadExt, FutureExt};
-        use tokio::net::TcpStream;
+        use futures::{FutureExt, StreamExt};
         use tokio::sync::mpsc;
 
         let org = stringify!($org);
@@ -18,15 +51,26 @@ macro_rules! iomod {
         let name = stringify!($name);
 
         let iomod_coords = format!("{}.{}.{}", org, ns, name);
-        println!("Starting AssemblyLift IO module {}", iomod_coords);
+        let iomod_version: &str = $version;
 
         let mut call_map: CallMap = $crate::__calls!($calls);
-        let mut call_channel: CallChannel = mpsc::channel(100);
+        let description = call_map.describe(&iomod_coords, iomod_version);
+        if std::env::args().skip(1).any(|arg| arg == "--describe") {
+            println!("{}", description.to_json());
+            std::process::exit(0);
+        }
 
-        let stream = TcpStream::connect(format!("{}:13555", $ip)).await.unwrap();
-        stream.set_nodelay(true).unwrap();
+        println!(
+            "Starting AssemblyLift IO module {}@{} (core {})",
+            iomod_coords,
+            iomod_version,
+            $crate::package::CORE_VERSION
+        );
+        let mut call_channel: CallChannel = mpsc::channel(100);
 
-        let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+        let registry_address =
+            $crate::transport::RegistryAddress::for_iomod(&format!("{}", $address)).unwrap();
+        let (reader, writer) = registry_address.connect().await.unwrap();
 
         let rpc_network = Box::new(twoparty::VatNetwork::new(
             reader,
@@ -44,29 +88,84 @@ macro_rules! iomod {
                 let rpc_task = tokio::task::spawn_local(Box::pin(rpc_system.map(|_| ())));
 
                 let mut register = registry.register_request();
+                register.get().set_iomod(capnp_rpc::new_client(
+                    Iomod::new(call_channel.0.clone()).with_description(description),
+                ));
+                register.get().set_coordinates(iomod_coords.as_str());
+                register.get().set_version(iomod_version);
                 register
                     .get()
-                    .set_iomod(capnp_rpc::new_client(Iomod::new(call_channel.0.clone())));
-                register.get().set_coordinates(iomod_coords.as_str());
-                register.send().promise.await.unwrap();
+                    .set_token(&std::env::var("ASML_REGISTRY_TOKEN").unwrap_or_default());
+                // a rejected IOmod exits with an error, so that its supervisor restarts it
+                if let Err(why) = register.send().promise.await {
+                    $crate::__tracing::error!("could not register IOmod {}: {}", iomod_coords, why);
+                    std::process::exit(1);
+                }
 
                 let call_task = tokio::task::spawn_local(async move {
-                    while let Some(mut call) = call_channel.1.recv().await {
-                        let coords = call.coords.as_str();
-                        let call_ptr = call_map.get(String::from(coords), call.input);
-
-                        let response = call_ptr.await;
-
-                        if let Err(why) = call
-                            .responder
-                            .send(CallResponse {
-                                coords: String::from(coords),
-                                payload: response,
-                            })
-                            .await
-                        {
-                            println!("ERROR {}", why)
-                        }
+                    while let Some(call) = call_channel.1.recv().await {
+                        let coords = call.coords;
+                        let responder = call.responder;
+                        let pending = call_map.get(coords.clone(), call.input);
+                        let iomod_coords = iomod_coords.clone();
+                        // each call runs as its own task, so that a slow call does not hold up the others
+                        tokio::task::spawn_local(async move {
+                            let mut chunks = match pending {
+                                Some(chunks) => chunks,
+                                None => futures::stream::once(futures::future::ready(Err(
+                                    IoError::new(
+                                        IoErrorKind::MethodNotFound,
+                                        format!("{} has no method {}", iomod_coords, coords),
+                                    ),
+                                )))
+                                .boxed(),
+                            };
+
+                            // each chunk is held back until the next is produced, so that a call
+                            // which does not stream is answered with a single response
+                            let mut last = None;
+                            let mut error = None;
+                            while let Some(chunk) = chunks.next().await {
+                                match chunk {
+                                    Ok(chunk) => {
+                                        let payload = match last.replace(chunk) {
+                                            Some(payload) => payload,
+                                            None => continue,
+                                        };
+                                        let partial = CallResponse {
+                                            coords: coords.clone(),
+                                            payload,
+                                            error: None,
+                                            partial: true,
+                                        };
+                                        // the call has been abandoned
+                                        if responder.send(partial).await.is_err() {
+                                            return;
+                                        }
+                                    }
+                                    Err(why) => {
+                                        error = Some(why);
+                                        break;
+                                    }
+                                }
+                            }
+                            let response = CallResponse {
+                                coords,
+                                payload: match error {
+                                    Some(_) => Vec::new(),
+                                    None => last.unwrap_or_default(),
+                                },
+                                error,
+                                partial: false,
+                            };
+
+                            if let Err(why) = responder.send(response).await {
+                                $crate::__tracing::warn!(
+                                    "call {} was abandoned before it completed",
+                                    why.0.coords
+                                );
+                            }
+                        });
                     }
                 });
 
@@ -79,12 +178,27 @@ macro_rules! iomod {
 #[macro_export]
 #[doc(hidden)]
 macro_rules! __calls {
-    ({ $( $call_name:ident => $call:expr ),* $(,)? }) => {{
+    ({ $($calls:tt)* }) => {{
         let mut call_map = CallMap::new();
-        $(
-            let call_name = stringify!($call_name);
-            call_map.map.insert(call_name, CallPtr::new($call));
-        )*
+        $crate::__insert_calls!(call_map; $($calls)*);
         call_map
     }};
 }
+
+#[macro_export]
+#[doc(hidden)]
+macro_rules! __insert_calls {
+    ($call_map:ident;) => {};
+    ($call_map:ident; $call_name:ident => typed $call:expr $(, $($rest:tt)*)?) => {
+        $call_map.map.insert(stringify!($call_name), CallPtr::typed($call));
+        $( $crate::__insert_calls!($call_map; $($rest)*); )?
+    };
+    ($call_map:ident; $call_name:ident => streaming $call:expr $(, $($rest:tt)*)?) => {
+        $call_map.map.insert(stringify!($call_name), CallPtr::streaming($call));
+        $( $crate::__insert_calls!($call_map; $($rest)*); )?
+    };
+    ($call_map:ident; $call_name:ident => $call:expr $(, $($rest:tt)*)?) => {
+        $call_map.map.insert(stringify!($call_name), CallPtr::new($call));
+        $( $crate::__insert_calls!($call_map; $($rest)*); )?
+    };
+}
@@ -1,40 +1,417 @@
+//! The IOmod package manifest, `iomod.toml`.
+//!
+//! ```toml
+//! [iomod]
+//! coordinates = "akkoro.std.http"
+//! version = "0.2.0"
+//! min_core_version = "0.4.0-alpha.10"
+//! methods = ["request"]
+//!
+//! [process]
+//! kind = "native"
+//! entrypoint = "http"
+//! arguments = ["--verbose"]
+//! working_dir = "."
+//!
+//! [process.environment]
+//! RUST_LOG = "info"
+//!
+//! [resources]
+//! memory_mb = 64
+//! cpu = 0.25
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use semver::Version;
 use serde::Deserialize;
 
-#[derive(Deserialize)]
+/// The version of the IOmod host library, which `min_core_version` is checked against
+pub const CORE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct IomodManifest {
     pub iomod: ManifestHeader,
     pub process: Process,
+    pub resources: Option<Resources>,
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(std::io::Error),
+    /// The manifest is not valid TOML, or does not match the manifest schema. `line` and
+    /// `column` are 1-based.
+    Parse {
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// The manifest parsed, but one of its fields has an invalid value
+    Invalid(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Io(why) => write!(f, "could not read IOmod manifest: {}", why),
+            ManifestError::Parse {
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => write!(f, "error parsing IOmod manifest at {}:{}: {}", line, column, message),
+            ManifestError::Parse { message, .. } => {
+                write!(f, "error parsing IOmod manifest: {}", message)
+            }
+            ManifestError::Invalid(why) => write!(f, "invalid IOmod manifest: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl From<toml::de::Error> for ManifestError {
+    fn from(error: toml::de::Error) -> Self {
+        let (line, column) = match error.line_col() {
+            Some((line, column)) => (Some(line + 1), Some(column + 1)),
+            None => (None, None),
+        };
+        // the message includes the position, which is reported separately
+        let message = error.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(idx) if line.is_some() => message[..idx].to_string(),
+            _ => message,
+        };
+        ManifestError::Parse {
+            line,
+            column,
+            message,
+        }
+    }
 }
 
 impl IomodManifest {
-    pub fn read(path: &std::path::PathBuf) -> Result<Self, std::io::Error> {
-        match std::fs::read_to_string(path) {
-            Ok(contents) => Ok(Self::from(contents)),
-            Err(why) => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                why.to_string(),
-            )),
+    /// Read and parse the manifest at `path`
+    pub fn load(path: &Path) -> Result<Self, ManifestError> {
+        let contents = std::fs::read_to_string(path).map_err(ManifestError::Io)?;
+        Self::parse(&contents)
+    }
+
+    #[deprecated(note = "use `IomodManifest::load`, which reports parse errors")]
+    #[allow(clippy::ptr_arg)] // the signature this has always had
+    pub fn read(path: &PathBuf) -> Result<Self, std::io::Error> {
+        Self::load(path).map_err(|why| match why {
+            ManifestError::Io(why) => why,
+            why => std::io::Error::new(std::io::ErrorKind::InvalidData, why.to_string()),
+        })
+    }
+
+    /// Parse a manifest from the contents of an `iomod.toml`
+    pub fn parse(contents: &str) -> Result<Self, ManifestError> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Check that each field of the manifest has a usable value
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        let invalid = |why: String| Err(ManifestError::Invalid(why));
+
+        let coordinates = &self.iomod.coordinates;
+        let parts = coordinates.split('.').collect::<Vec<_>>();
+        if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+            return invalid(format!(
+                "coordinates {} are not of the form org.namespace.name",
+                coordinates
+            ));
         }
+        if let Err(why) = Version::parse(&self.iomod.version) {
+            return invalid(format!("version {}: {}", self.iomod.version, why));
+        }
+        if let Some(min_core_version) = &self.iomod.min_core_version {
+            if let Err(why) = Version::parse(min_core_version) {
+                return invalid(format!("min_core_version {}: {}", min_core_version, why));
+            }
+        }
+
+        let mut methods = HashSet::new();
+        for method in self.iomod.methods.iter().flatten() {
+            if method.is_empty() || method.contains(|c: char| c == '.' || c == '@' || c.is_whitespace()) {
+                return invalid(format!("method name {:?} is not valid", method));
+            }
+            if !methods.insert(method) {
+                return invalid(format!("method {} is declared more than once", method));
+            }
+        }
+
+        if self.process.entrypoint.is_empty() {
+            return invalid("process.entrypoint is empty".to_string());
+        }
+        for (path, field) in [
+            (Some(&self.process.entrypoint), "process.entrypoint"),
+            (self.process.working_dir.as_ref(), "process.working_dir"),
+        ] {
+            if let Some(path) = path {
+                if Path::new(path).is_absolute() || path.split('/').any(|c| c == "..") {
+                    return invalid(format!("{} {} is not within the package", field, path));
+                }
+            }
+        }
+
+        if let Some(resources) = &self.resources {
+            if resources.memory_mb == Some(0) {
+                return invalid("resources.memory_mb must be greater than 0".to_string());
+            }
+            if matches!(resources.cpu, Some(cpu) if !(cpu > 0f32 && cpu.is_finite())) {
+                return invalid("resources.cpu must be greater than 0".to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the IOmod supports the host library version `core_version`
+    pub fn check_core_version(&self, core_version: &str) -> Result<(), ManifestError> {
+        let min_core_version = match &self.iomod.min_core_version {
+            Some(min_core_version) => min_core_version,
+            None => return Ok(()),
+        };
+        match (Version::parse(min_core_version), Version::parse(core_version)) {
+            (Ok(min), Ok(core)) if core >= min => Ok(()),
+            (Ok(_), Ok(_)) => Err(ManifestError::Invalid(format!(
+                "{} requires AssemblyLift core {} or later, but this is {}",
+                self.iomod.coordinates, min_core_version, core_version
+            ))),
+            (Err(why), _) | (_, Err(why)) => Err(ManifestError::Invalid(why.to_string())),
+        }
+    }
+}
+
+impl FromStr for IomodManifest {
+    type Err = ManifestError;
+
+    fn from_str(contents: &str) -> Result<Self, Self::Err> {
+        Self::parse(contents)
     }
 }
 
+/// Deprecated: this panics if `contents` is not a valid manifest; use `IomodManifest::parse`.
+/// Trait impls cannot carry `#[deprecated]`, so this is kept for compatibility only.
 impl From<String> for IomodManifest {
-    fn from(string: String) -> Self {
-        match toml::from_str(&string) {
+    fn from(contents: String) -> Self {
+        match Self::parse(&contents) {
             Ok(manifest) => manifest,
-            Err(why) => panic!("error parsing IomodManifest: {}", why.to_string()),
+            Err(why) => panic!("could not parse IOmod manifest: {}", why),
         }
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct ManifestHeader {
     pub coordinates: String,
     pub version: String,
+    /// The oldest AssemblyLift core the IOmod runs against
+    pub min_core_version: Option<String>,
+    /// The names of the methods the IOmod serves. When set, `describe` must report exactly
+    /// these, and capabilities naming any other method of the IOmod are reported at startup.
+    pub methods: Option<Vec<String>>,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Process {
+    #[serde(default)]
+    pub kind: ProcessKind,
     pub entrypoint: String,
     pub arguments: Option<Vec<String>>,
+    /// Variables set in the environment of the IOmod process, in addition to those it inherits
+    pub environment: Option<HashMap<String, String>>,
+    /// The directory the IOmod process is started in, relative to the package
+    pub working_dir: Option<String>,
+}
+
+/// What kind of program an IOmod's entrypoint is
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProcessKind {
+    /// An executable, run as a process of its own
+    #[default]
+    Native,
+    /// A WebAssembly module, run inside the runtime's process
+    Wasm,
+}
+
+/// The resources an IOmod process needs
+#[derive(Clone, Debug, Deserialize)]
+pub struct Resources {
+    /// The most memory the IOmod may use. Native IOmods are started with their data segment
+    /// limited to this, and WASM IOmods cannot grow their memory past it.
+    pub memory_mb: Option<u64>,
+    /// Fractional CPU cores. This is a hint only, which is logged when the IOmod starts.
+    pub cpu: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[iomod]
+coordinates = "akkoro.std.http"
+version = "0.1.0"
+min_core_version = "0.3.0"
+methods = ["request", "get"]
+
+[process]
+entrypoint = "bin/http"
+arguments = ["--verbose"]
+working_dir = "bin"
+
+[process.environment]
+RUST_LOG = "info"
+
+[resources]
+memory_mb = 64
+cpu = 0.5
+"#;
+
+    fn valid_manifest() -> IomodManifest {
+        IomodManifest::parse(MANIFEST).unwrap()
+    }
+
+    fn assert_invalid(manifest: IomodManifest) {
+        match manifest.validate() {
+            Err(ManifestError::Invalid(_)) => {}
+            other => panic!("expected an invalid manifest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_manifest() {
+        let manifest = valid_manifest();
+        assert_eq!(manifest.iomod.coordinates, "akkoro.std.http");
+        assert_eq!(
+            manifest.iomod.methods,
+            Some(vec!["request".into(), "get".into()])
+        );
+        assert_eq!(manifest.process.kind, ProcessKind::Native);
+        assert_eq!(manifest.process.entrypoint, "bin/http");
+        assert_eq!(
+            manifest
+                .process
+                .environment
+                .as_ref()
+                .unwrap()
+                .get("RUST_LOG"),
+            Some(&"info".to_string())
+        );
+        let resources = manifest.resources.as_ref().unwrap();
+        assert_eq!(resources.memory_mb, Some(64));
+        assert_eq!(resources.cpu, Some(0.5));
+        manifest.validate().unwrap();
+    }
+
+    #[test]
+    fn parse_wasm_kind() {
+        let manifest = IomodManifest::parse(
+            "[iomod]\ncoordinates = \"a.b.c\"\nversion = \"1.0.0\"\n\n\
+             [process]\nkind = \"wasm\"\nentrypoint = \"iomod.wasm\"\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.process.kind, ProcessKind::Wasm);
+        assert!(manifest.resources.is_none());
+        manifest.validate().unwrap();
+    }
+
+    #[test]
+    fn parse_error_has_position() {
+        let contents = MANIFEST.replace("version = \"0.1.0\"", "version = 0.1.0");
+        match IomodManifest::parse(&contents) {
+            Err(ManifestError::Parse {
+                line: Some(4),
+                column: Some(_),
+                ..
+            }) => {}
+            other => panic!("expected a parse error on line 4, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_error_for_missing_field() {
+        let contents = MANIFEST.replace("entrypoint = \"bin/http\"", "");
+        match IomodManifest::parse(&contents) {
+            Err(ManifestError::Parse { message, .. }) => assert!(message.contains("entrypoint")),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_coordinates() {
+        for coordinates in &["akkoro.std", "akkoro..http", "a.b.c.d", ""] {
+            let mut manifest = valid_manifest();
+            manifest.iomod.coordinates = coordinates.to_string();
+            assert_invalid(manifest);
+        }
+    }
+
+    #[test]
+    fn invalid_versions() {
+        let mut manifest = valid_manifest();
+        manifest.iomod.version = "1.0".into();
+        assert_invalid(manifest);
+
+        let mut manifest = valid_manifest();
+        manifest.iomod.min_core_version = Some("latest".into());
+        assert_invalid(manifest);
+    }
+
+    #[test]
+    fn invalid_methods() {
+        for method in &["", "std.get", "get@1", "get all"] {
+            let mut manifest = valid_manifest();
+            manifest.iomod.methods = Some(vec![method.to_string()]);
+            assert_invalid(manifest);
+        }
+
+        let mut manifest = valid_manifest();
+        manifest.iomod.methods = Some(vec!["get".into(), "get".into()]);
+        assert_invalid(manifest);
+    }
+
+    #[test]
+    fn paths_outside_the_package() {
+        for entrypoint in &["", "/usr/bin/http", "../http", "bin/../../http"] {
+            let mut manifest = valid_manifest();
+            manifest.process.entrypoint = entrypoint.to_string();
+            assert_invalid(manifest);
+        }
+
+        let mut manifest = valid_manifest();
+        manifest.process.working_dir = Some("..".into());
+        assert_invalid(manifest);
+    }
+
+    #[test]
+    fn invalid_resources() {
+        let mut manifest = valid_manifest();
+        manifest.resources.as_mut().unwrap().memory_mb = Some(0);
+        assert_invalid(manifest);
+
+        for cpu in &[0f32, -1f32, f32::NAN, f32::INFINITY] {
+            let mut manifest = valid_manifest();
+            manifest.resources.as_mut().unwrap().cpu = Some(*cpu);
+            assert_invalid(manifest);
+        }
+    }
+
+    #[test]
+    fn core_version() {
+        let manifest = valid_manifest();
+        manifest.check_core_version("0.3.0").unwrap();
+        manifest.check_core_version("0.4.1").unwrap();
+        assert!(manifest.check_core_version("0.2.9").is_err());
+
+        let mut manifest = manifest;
+        manifest.iomod.min_core_version = None;
+        manifest.check_core_version("0.0.1").unwrap();
+    }
 }
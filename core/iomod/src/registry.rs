@@ -4,22 +4,67 @@
 //! The call registry is maintained in-memory. A thread is spawned which handles
 //! RPC connections from IOmods and handles IOmod registration. This thread also
 //! services call invocations to registered IOmods via MPSC receiver (sent from `Threader`).
+//! Each call is dispatched as its own task, so responses may be delivered out of order.
+//!
+//! A registration lives as long as the connection it was made over, and an IOmod which
+//! registers again replaces its previous registration. Registered IOmods are pinged
+//! periodically, and any which do not respond are reported as unhealthy.
+//!
+//! Several instances of an IOmod may register at the same coordinates, each over its own
+//! connection. Calls are spread across them by least-outstanding, and a call which fails
+//! to reach one instance fails over to the next.
+//!
+//! IOmods must present the registry's token to register, and may only register at the
+//! coordinates of a dependency declared in the service manifest.
+//!
+//! IOmods register with their version, and calls are routed to an instance whose version
+//! satisfies the call's semver requirement. Functions may therefore depend on different
+//! versions of the same IOmod in one runtime.
+//!
+//! IOmods are called with `invokeStreaming`, and each chunk of a streamed response is forwarded
+//! to the caller as an `IOMOD_CHUNK` message as it arrives, ahead of the final `IOMOD_RESPONSE`.
+//! IOmods which predate streaming are called with `invoke`. A call which has delivered part of its
+//! response does not fail over to another instance.
+//!
+//! A message with the payload type `IOMOD_DESCRIBE` asks the IOmod at its coordinates to describe
+//! its methods, and is answered with the JSON of its `IomodDescription`.
+//!
+//! A call to coordinates at which no matching IOmod is registered is held for a grace period,
+//! so that calls made while IOmods are still starting wait for them rather than failing.
+//! Runtimes may also wait on `RegistryReadiness` for every declared IOmod before accepting
+//! invocations.
+//!
+//! IOmods running inside the runtime's own process (such as WASM IOmods) are registered through
+//! `RegistryHandle::register_local` rather than over RPC, and are served by an in-process
+//! `iomod::Client`. Built-in IOmods implementing `InProcessIomod` are registered through
+//! `RegistryHandle::register_in_process`, and are called directly without going through RPC
+//! at all.
 
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use capnp::capability::Promise;
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-use futures::{AsyncReadExt, FutureExt, TryFutureExt};
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tracing::{error, info};
+use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use semver::{Version, VersionReq};
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+use assemblylift_core_io_common::iomod::{IoError, IoErrorKind};
 
-use crate::iomod_capnp::{agent, iomod, registry};
-use crate::Agent;
+use crate::iomod_capnp::{agent, iomod, registry, response_stream};
+use crate::transport::{RegistryAddress, RegistryListener};
+use crate::schema::IomodDescription;
+use crate::{
+    malformed_response, read_description, read_error, read_response, Agent, CallRequest,
+    InProcessIomod, Iomod,
+};
 
 pub type RegistryTx = mpsc::Sender<RegistryChannelMessage>;
 pub type RegistryRx = mpsc::Receiver<RegistryChannelMessage>;
@@ -29,6 +74,9 @@ pub type ClientPair = (iomod::Client, agent::Client);
 
 pub struct Registry {
     modules: ModuleMap,
+    config: Arc<RegistryConfig>,
+    registered: Rc<RegisteredTx>,
+    connection_id: u64,
 }
 
 #[derive(Debug)]
@@ -54,31 +102,312 @@ impl std::error::Error for RegistryError {}
 pub struct RegistryChannelMessage {
     pub iomod_coords: String,
     pub method_name: String,
+    /// The semver requirement the IOmod must satisfy, if any
+    pub version: Option<String>,
     pub payload_type: &'static str,
     pub payload: Vec<u8>,
+    /// Set on a response if the call failed, in which case `payload` is empty
+    pub error: Option<IoError>,
     pub responder: Option<RegistryTx>,
 }
 
-pub type ModuleMap = Arc<Box<RefCell<HashMap<String, agent::Client>>>>;
+/// How calls reach a registered IOmod
+#[derive(Clone)]
+pub enum Endpoint {
+    /// An IOmod served over RPC
+    Rpc {
+        agent: agent::Client,
+        iomod: iomod::Client,
+    },
+    /// An IOmod called directly in the registry's thread
+    InProcess(Arc<dyn InProcessIomod>),
+}
+
+impl Endpoint {
+    fn rpc(iomod: iomod::Client) -> Self {
+        let module: Rc<RefCell<iomod::Client>> = Rc::new(RefCell::new(iomod.clone()));
+        let agent: agent::Client = capnp_rpc::new_client(Agent::new(module));
+        Endpoint::Rpc { agent, iomod }
+    }
+}
+
+/// An IOmod registered over one of the registry's RPC connections, or from within the runtime
+#[derive(Clone)]
+pub struct Registration {
+    pub endpoint: Endpoint,
+    /// The version the IOmod registered with. IOmods which predate versioned registration
+    /// have none, and only serve calls without a version requirement.
+    pub version: Option<Version>,
+    /// The connection the IOmod registered over; the registration is removed when it closes
+    pub connection_id: u64,
+    /// False if the IOmod did not respond to its last ping
+    pub healthy: bool,
+    /// The number of calls dispatched to the IOmod which have not completed
+    pub outstanding: usize,
+}
+
+/// Maps IOmod coordinates to every instance registered at them
+pub type ModuleMap = Rc<RefCell<HashMap<String, Vec<Registration>>>>;
+
+/// Publishes the coordinates at which at least one IOmod is registered
+type RegisteredTx = watch::Sender<HashSet<String>>;
+type RegisteredRx = watch::Receiver<HashSet<String>>;
+
+/// How often each registered IOmod is pinged
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a ping may take before the IOmod is reported as unhealthy
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the registry waits to accept another connection after failing to accept one
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// How long a call waits for an IOmod to register, unless `ASML_REGISTRY_GRACE_SECONDS` is set
+const DEFAULT_REGISTRATION_GRACE: Duration = Duration::from_secs(10);
+
+/// Configuration of the registry
+#[derive(Clone, Debug)]
+pub struct RegistryConfig {
+    pub address: RegistryAddress,
+    /// The token an IOmod must present to register. Any IOmod may register if this is `None`.
+    pub token: Option<String>,
+    /// The coordinates IOmods may register at. Any coordinates are accepted if this is `None`.
+    pub declared_coordinates: Option<HashSet<String>>,
+    /// How long a call to coordinates with no matching IOmod waits for one to register
+    pub registration_grace: Duration,
+}
+
+impl RegistryConfig {
+    /// Read the registry configuration from the environment.
+    ///
+    /// The token is read from `ASML_REGISTRY_TOKEN`. If it is not set a token is generated, which
+    /// the IOmod processes launched by the runtime are given with `iomod_environment`.
+    /// The declared coordinates are those of the dependencies in `ASML_IOMOD_VERSIONS`, and the
+    /// registration grace period is read in seconds from `ASML_REGISTRY_GRACE_SECONDS`.
+    pub fn from_env() -> Result<Self, RegistryError> {
+        let token = match std::env::var("ASML_REGISTRY_TOKEN") {
+            Ok(token) if !token.is_empty() => token,
+            _ => rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect(),
+        };
+        let declared_coordinates = std::env::var("ASML_IOMOD_VERSIONS").ok().map(|versions| {
+            versions
+                .split(',')
+                .filter_map(|dependency| dependency.split_once('='))
+                .map(|(coords, _)| coords.trim().to_string())
+                .collect()
+        });
+
+        let registration_grace = std::env::var("ASML_REGISTRY_GRACE_SECONDS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REGISTRATION_GRACE);
+
+        Ok(Self {
+            address: RegistryAddress::for_registry()?,
+            token: Some(token),
+            declared_coordinates,
+            registration_grace,
+        })
+    }
+
+    /// The environment an IOmod process launched by the runtime needs to find the registry and
+    /// register with it
+    pub fn iomod_environment(&self) -> HashMap<String, String> {
+        let mut environment = HashMap::new();
+        environment.insert(
+            "ASML_REGISTRY_ADDRESS".to_string(),
+            self.address.to_string(),
+        );
+        if let Some(token) = &self.token {
+            environment.insert("ASML_REGISTRY_TOKEN".to_string(), token.clone());
+        }
+        environment
+    }
+}
+
+/// Reports whether an IOmod has registered at each of the declared coordinates
+#[derive(Clone)]
+pub struct RegistryReadiness {
+    registered: RegisteredRx,
+    declared: Option<HashSet<String>>,
+    grace: Duration,
+}
+
+impl RegistryReadiness {
+    /// The declared coordinates at which no IOmod has registered yet
+    pub fn missing(&self) -> Vec<String> {
+        let registered = self.registered.borrow();
+        let mut missing = self
+            .declared
+            .iter()
+            .flatten()
+            .filter(|coords| !registered.contains(*coords))
+            .cloned()
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// True if an IOmod has registered at every declared coordinate
+    pub fn is_ready(&self) -> bool {
+        self.missing().is_empty()
+    }
+
+    /// Wait for an IOmod to register at every declared coordinate, for at most the registration
+    /// grace period
+    pub async fn wait(&mut self) -> Result<(), RegistryError> {
+        let deadline = tokio::time::Instant::now() + self.grace;
+        while !self.is_ready() {
+            let changed = tokio::time::timeout_at(deadline, self.registered.changed()).await;
+            if !matches!(changed, Ok(Ok(()))) {
+                return Err(RegistryError::new(format!(
+                    "no IOmod registered within {:?} at {}",
+                    self.grace,
+                    self.missing().join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An IOmod running in the runtime's process
+struct LocalRegistration {
+    coordinates: String,
+    version: Version,
+    iomod: LocalIomod,
+}
+
+enum LocalIomod {
+    /// Serves the calls sent to the channel, through an in-process `iomod::Client`
+    Channel(mpsc::Sender<CallRequest>),
+    InProcess(Arc<dyn InProcessIomod>),
+}
+
+/// A handle to a spawned registry
+#[derive(Clone)]
+pub struct RegistryHandle {
+    readiness: RegistryReadiness,
+    local_tx: mpsc::UnboundedSender<LocalRegistration>,
+}
+
+impl RegistryHandle {
+    pub fn readiness(&self) -> RegistryReadiness {
+        self.readiness.clone()
+    }
+
+    /// Register an IOmod running in this process at `coordinates`, which serves the calls sent
+    /// to `tx`
+    pub fn register_local(
+        &self,
+        coordinates: &str,
+        version: &str,
+        tx: mpsc::Sender<CallRequest>,
+    ) -> Result<(), RegistryError> {
+        self.add_local(coordinates, version, LocalIomod::Channel(tx))
+    }
+
+    /// Register `iomod` at `coordinates`. Calls to it are made directly by the registry, without
+    /// RPC.
+    pub fn register_in_process(
+        &self,
+        coordinates: &str,
+        version: &str,
+        iomod: Arc<dyn InProcessIomod>,
+    ) -> Result<(), RegistryError> {
+        self.add_local(coordinates, version, LocalIomod::InProcess(iomod))
+    }
+
+    fn add_local(
+        &self,
+        coordinates: &str,
+        version: &str,
+        iomod: LocalIomod,
+    ) -> Result<(), RegistryError> {
+        let version = Version::parse(version).map_err(|why| {
+            RegistryError::new(format!(
+                "invalid version {} for IOmod at {}: {}",
+                version, coordinates, why
+            ))
+        })?;
+        if let Some(declared) = &self.readiness.declared {
+            if !declared.contains(coordinates) {
+                return Err(RegistryError::new(format!(
+                    "{} is not a declared IOmod dependency",
+                    coordinates
+                )));
+            }
+        }
+        self.local_tx
+            .send(LocalRegistration {
+                coordinates: coordinates.to_string(),
+                version,
+                iomod,
+            })
+            .map_err(|_| RegistryError::new("the registry has exited".to_string()))
+    }
+}
+
+/// Spawn the registry, configured from the environment (see `RegistryConfig::from_env`)
+pub fn spawn_registry(rx: RegistryRx) -> Result<RegistryHandle, RegistryError> {
+    spawn_registry_with(rx, RegistryConfig::from_env()?)
+}
+
+/// Spawn the registry with `config`
+pub fn spawn_registry_with(
+    mut rx: RegistryRx,
+    config: RegistryConfig,
+) -> Result<RegistryHandle, RegistryError> {
+    let (registered_tx, registered_rx) = watch::channel(HashSet::new());
+    let (local_tx, mut local_rx) = mpsc::unbounded_channel::<LocalRegistration>();
+    let handle = RegistryHandle {
+        readiness: RegistryReadiness {
+            registered: registered_rx.clone(),
+            declared: config.declared_coordinates.clone(),
+            grace: config.registration_grace,
+        },
+        local_tx,
+    };
 
-pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
-    std::thread::spawn(|| {
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
 
-        tokio::task::LocalSet::new().block_on(&mut rt, async {
-            let modules: ModuleMap = Arc::new(Box::new(RefCell::new(HashMap::new())));
+        tokio::task::LocalSet::new().block_on(&rt, async {
+            let modules: ModuleMap = Rc::new(RefCell::new(HashMap::new()));
+            let registered = Rc::new(registered_tx);
+            let grace = config.registration_grace;
+            let next_connection_id = Rc::new(Cell::new(0u64));
 
             let rpc_modules = modules.clone();
+            let rpc_registered = registered.clone();
+            let rpc_connection_id = next_connection_id.clone();
             let rpc_task = tokio::task::spawn_local(async move {
-                let listener = TcpListener::bind("0.0.0.0:13555").await.unwrap();
-                let registry_client: registry::Client =
-                    capnp_rpc::new_client(Registry::new(rpc_modules));
-
-                while let Ok((stream, _)) = listener.accept().await {
-                    stream.set_nodelay(true).unwrap();
+                let registered = rpc_registered;
+                let address = config.address.clone();
+                let config = Arc::new(config);
+                let listener = match RegistryListener::bind(&address).await {
+                    Ok(listener) => listener,
+                    Err(why) => {
+                        error!("could not bind registry to {}: {}", address, why);
+                        return;
+                    }
+                };
+                info!("registry listening on {}", address);
 
-                    let (reader, writer) =
-                        tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+                loop {
+                    let (reader, writer) = match listener.accept().await {
+                        Ok(halves) => halves,
+                        Err(why) => {
+                            // e.g. the process is out of file descriptors; the listener is still
+                            // usable, so back off for the condition to clear and keep accepting
+                            warn!("registry could not accept a connection: {}", why);
+                            tokio::time::sleep(ACCEPT_BACKOFF).await;
+                            continue;
+                        }
+                    };
+                    let connection_id = rpc_connection_id.replace(rpc_connection_id.get() + 1);
 
                     let rpc_network = twoparty::VatNetwork::new(
                         reader,
@@ -87,53 +416,107 @@ pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
                         Default::default(),
                     );
 
+                    // each connection has its own registry, so that its registrations can be
+                    // removed when it closes
+                    let registry_client: registry::Client = capnp_rpc::new_client(Registry::new(
+                        rpc_modules.clone(),
+                        config.clone(),
+                        registered.clone(),
+                        connection_id,
+                    ));
                     let rpc_system =
-                        RpcSystem::new(Box::new(rpc_network), Some(registry_client.clone().client));
+                        RpcSystem::new(Box::new(rpc_network), Some(registry_client.client));
 
-                    tokio::task::spawn_local(Box::pin(
-                        rpc_system
-                            .map_err(|e| println!("error: {:?}", e))
-                            .map(|_| ()),
-                    ));
+                    let connection_modules = rpc_modules.clone();
+                    let connection_registered = registered.clone();
+                    tokio::task::spawn_local(async move {
+                        if let Err(why) = rpc_system.await {
+                            error!("IOmod connection {} failed: {}", connection_id, why);
+                        }
+                        RefCell::borrow_mut(&connection_modules).retain(|coords, instances| {
+                            instances.retain(|registration| {
+                                if registration.connection_id != connection_id {
+                                    return true;
+                                }
+                                info!("IOmod at {} disconnected", coords);
+                                false
+                            });
+                            !instances.is_empty()
+                        });
+                        publish_registered(&connection_modules, &connection_registered);
+                    });
+                }
+            });
+
+            let ping_modules = modules.clone();
+            let ping_task = tokio::task::spawn_local(async move {
+                let mut interval = tokio::time::interval(PING_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    // in-process IOmods have no connection which could fail, so are not pinged
+                    let registrations = RefCell::borrow(&ping_modules)
+                        .iter()
+                        .flat_map(|(coords, instances)| {
+                            instances.iter().filter_map(move |registration| {
+                                match &registration.endpoint {
+                                    Endpoint::Rpc { iomod, .. } => Some((
+                                        coords.clone(),
+                                        registration.connection_id,
+                                        iomod.clone(),
+                                    )),
+                                    Endpoint::InProcess(_) => None,
+                                }
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    for (coords, connection_id, iomod) in registrations {
+                        tokio::task::spawn_local(ping(
+                            ping_modules.clone(),
+                            coords,
+                            connection_id,
+                            iomod,
+                        ));
+                    }
                 }
             });
 
             let rx_modules = modules.clone();
             let rx_task = tokio::task::spawn_local(async move {
                 while let Some(msg) = rx.recv().await {
-                    let responder = msg.responder.unwrap();
-                    let coords = msg.iomod_coords;
-                    let method = msg.method_name;
-                    let input = msg.payload.as_slice();
-
-                    let modules = RefCell::borrow(&rx_modules);
-                    match modules.get(&coords) {
-                        Some(agent) => {
-                            info!("invoking call @ {}.{}", coords.clone(), method.clone());
-                            let mut invoke = agent.invoke_request();
-                            invoke.get().set_coordinates(&method);
-                            invoke.get().set_input(input);
-                            let results = invoke.send().promise.await.unwrap();
-                            let response_payload =
-                                Vec::from(results.get().unwrap().get_result().unwrap());
-
-                            responder
-                                .send(RegistryChannelMessage {
-                                    iomod_coords: coords,
-                                    method_name: method,
-                                    payload_type: "IOMOD_RESPONSE",
-                                    payload: response_payload,
-                                    responder: None,
-                                })
-                                .await
-                                .unwrap();
+                    // each call is dispatched as its own task, and responds as soon as it completes
+                    tokio::task::spawn_local(dispatch(
+                        rx_modules.clone(),
+                        registered_rx.clone(),
+                        grace,
+                        msg,
+                    ));
+                }
+            });
+
+            let local_modules = modules.clone();
+            let local_task = tokio::task::spawn_local(async move {
+                while let Some(local) = local_rx.recv().await {
+                    // a local IOmod has a connection of its own, which is never closed
+                    let connection_id = next_connection_id.replace(next_connection_id.get() + 1);
+                    let endpoint = match local.iomod {
+                        LocalIomod::Channel(tx) => {
+                            Endpoint::rpc(capnp_rpc::new_client(Iomod::new(tx)))
                         }
-                        None => panic!("no IOmod registered at {}", coords),
-                    }
+                        LocalIomod::InProcess(iomod) => Endpoint::InProcess(iomod),
+                    };
+                    add_registration(
+                        &local_modules,
+                        &registered,
+                        local.coordinates,
+                        endpoint,
+                        Some(local.version),
+                        connection_id,
+                    );
                 }
             });
 
-            let (rpc_result, rx_result) = tokio::join!(rpc_task, rx_task);
+            let (rpc_result, rx_result, ping_result, local_result) =
+                tokio::join!(rpc_task, rx_task, ping_task, local_task);
 
             if rpc_result.is_err() {
                 error!(
@@ -148,35 +531,684 @@ pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
                     Some(rx_result.err())
                 );
             }
+
+            if ping_result.is_err() {
+                error!(
+                    "registry ping task exited with error {:?}",
+                    Some(ping_result.err())
+                );
+            }
+
+            if local_result.is_err() {
+                error!(
+                    "registry local task exited with error {:?}",
+                    Some(local_result.err())
+                );
+            }
         })
     });
 
-    Ok(())
+    Ok(handle)
+}
+
+/// Add the IOmod reached at `endpoint` at `coordinates` to `modules`. An IOmod registering again
+/// over the same connection replaces its stale registration, while one registering over a new
+/// connection is another instance at the same coordinates.
+fn add_registration(
+    modules: &ModuleMap,
+    registered: &RegisteredTx,
+    coordinates: String,
+    endpoint: Endpoint,
+    version: Option<Version>,
+    connection_id: u64,
+) {
+    let label = match &version {
+        Some(version) => format!("{}@{}", coordinates, version),
+        None => coordinates.clone(),
+    };
+    let registration = Registration {
+        endpoint,
+        version,
+        connection_id,
+        healthy: true,
+        outstanding: 0,
+    };
+
+    {
+        let mut modules = RefCell::borrow_mut(modules);
+        let instances = modules.entry(coordinates).or_default();
+        match instances
+            .iter_mut()
+            .find(|r| r.connection_id == connection_id)
+        {
+            Some(stale) => {
+                *stale = registration;
+                info!("re-registered IOmod at coordinates {}", label);
+            }
+            None => {
+                instances.push(registration);
+                info!(
+                    "registered IOmod at coordinates {} ({} instances)",
+                    label,
+                    instances.len()
+                );
+            }
+        }
+    }
+    publish_registered(modules, registered);
+}
+
+/// Publish the coordinates at which IOmods are currently registered
+fn publish_registered(modules: &ModuleMap, registered: &RegisteredTx) {
+    let coordinates = RefCell::borrow(modules).keys().cloned().collect();
+    registered.send_replace(coordinates);
+}
+
+/// Invoke the call in `msg` on the IOmod registered at its coordinates, or describe the IOmod if
+/// `msg` is an `IOMOD_DESCRIBE`, and send the outcome to its responder. If no matching IOmod is
+/// registered, the call waits up to `grace` for one.
+async fn dispatch(
+    modules: ModuleMap,
+    registered: RegisteredRx,
+    grace: Duration,
+    msg: RegistryChannelMessage,
+) {
+    let responder = msg.responder.unwrap();
+    let coords = msg.iomod_coords;
+    let method = msg.method_name;
+    let version = msg.version;
+    let input = msg.payload.as_slice();
+
+    let result = match version.as_deref().map(VersionReq::parse).transpose() {
+        Ok(requirement) => {
+            let requirement = requirement.as_ref();
+            await_registration(&modules, registered, &coords, requirement, grace).await;
+            match msg.payload_type {
+                "IOMOD_DESCRIBE" => describe_instance(&modules, &coords, requirement).await,
+                _ => {
+                    call_instances(&modules, &coords, &method, requirement, input, &responder)
+                        .await
+                }
+            }
+        }
+        Err(why) => Err(IoError::new(
+            IoErrorKind::InvalidInput,
+            format!("invalid version requirement for {}: {}", coords, why),
+        )),
+    };
+    if let Err(error) = &result {
+        error!("call @ {}.{} failed: {}", coords, method, error);
+    }
+
+    let (payload, error) = match result {
+        Ok(payload) => (payload, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+    if let Err(why) = responder
+        .send(RegistryChannelMessage {
+            iomod_coords: coords,
+            method_name: method,
+            version: None,
+            payload_type: "IOMOD_RESPONSE",
+            payload,
+            error,
+            responder: None,
+        })
+        .await
+    {
+        error!("could not respond to call: {}", why);
+    }
+}
+
+/// Wait for an instance satisfying `requirement` to be registered at `coords`, for at most `grace`
+async fn await_registration(
+    modules: &ModuleMap,
+    mut registered: RegisteredRx,
+    coords: &str,
+    requirement: Option<&VersionReq>,
+    grace: Duration,
+) {
+    let deadline = tokio::time::Instant::now() + grace;
+    let is_registered = || {
+        RefCell::borrow(modules)
+            .get(coords)
+            .is_some_and(|instances| instances.iter().any(|r| satisfies(r, requirement)))
+    };
+    if is_registered() {
+        return;
+    }
+    info!("waiting for an IOmod to register at {}", coords);
+    while !is_registered() {
+        let changed = tokio::time::timeout_at(deadline, registered.changed()).await;
+        if !matches!(changed, Ok(Ok(()))) {
+            break;
+        }
+    }
+}
+
+/// Invoke `method` on an instance at `coords` satisfying `requirement`, failing over to the
+/// next instance if one cannot be reached. The chunks of a streamed response are forwarded to
+/// `responder` as they arrive.
+async fn call_instances(
+    modules: &ModuleMap,
+    coords: &str,
+    method: &str,
+    requirement: Option<&VersionReq>,
+    input: &[u8],
+    responder: &RegistryTx,
+) -> Result<Vec<u8>, IoError> {
+    let mut tried = Vec::new();
+    let mut result = Err(IoError::new(
+        IoErrorKind::NotFound,
+        match requirement {
+            Some(requirement) => format!("no IOmod registered at {} matching {}", coords, requirement),
+            None => format!("no IOmod registered at {}", coords),
+        },
+    ));
+    while let Some((connection_id, endpoint)) =
+        select_instance(modules, coords, requirement, &tried)
+    {
+        info!(
+            "invoking call @ {}.{} on connection {}",
+            coords, method, connection_id
+        );
+        let sink = ResponseSink::new(coords, method, responder.clone());
+        let state = sink.state.clone();
+        let outcome = match endpoint {
+            Endpoint::Rpc { agent, iomod } => {
+                invoke_streaming(iomod, agent, method, input, sink).await
+            }
+            Endpoint::InProcess(iomod) => Ok(iomod.call(method, input.to_vec()).await),
+        };
+        release_instance(modules, coords, connection_id);
+        tried.push(connection_id);
+        match outcome {
+            Ok(response) => {
+                result = response;
+                break;
+            }
+            // the instance did not deliver a response, so fail over to the next one
+            Err(why) => {
+                warn!(
+                    "call @ {}.{} on connection {} failed: {}",
+                    coords, method, connection_id, why
+                );
+                result = Err(IoError::new(
+                    IoErrorKind::Unavailable,
+                    format!("could not reach IOmod at {}: {}", coords, why),
+                ));
+                // the caller already holds part of the response, which another instance would
+                // deliver again
+                if state.written.get() {
+                    break;
+                }
+            }
+        }
+    }
+    result
+}
+
+/// Describe an instance at `coords` satisfying `requirement`, as the JSON of its
+/// `IomodDescription`
+async fn describe_instance(
+    modules: &ModuleMap,
+    coords: &str,
+    requirement: Option<&VersionReq>,
+) -> Result<Vec<u8>, IoError> {
+    let not_described = || {
+        IoError::new(
+            IoErrorKind::MethodNotFound,
+            format!("the IOmod at {} does not describe its methods", coords),
+        )
+    };
+    let (connection_id, endpoint) = select_instance(modules, coords, requirement, &[])
+        .ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::NotFound,
+                format!("no IOmod registered at {}", coords),
+            )
+        })?;
+    let description = match endpoint {
+        Endpoint::Rpc { iomod, .. } => describe(iomod).await.map_err(|why| match why.kind {
+            capnp::ErrorKind::Unimplemented => not_described(),
+            _ => IoError::new(
+                IoErrorKind::Unavailable,
+                format!("could not describe IOmod at {}: {}", coords, why),
+            ),
+        }),
+        Endpoint::InProcess(iomod) => iomod.describe().ok_or_else(not_described),
+    };
+    release_instance(modules, coords, connection_id);
+
+    serde_json::to_vec(&description?).map_err(|why| {
+        IoError::new(
+            IoErrorKind::Failed,
+            format!("could not serialize description: {}", why),
+        )
+    })
+}
+
+/// Ask `iomod` to describe its methods
+async fn describe(iomod: iomod::Client) -> Result<IomodDescription, capnp::Error> {
+    let results = iomod.describe_request().send().promise.await?;
+    read_description(results.get()?.get_description()?)
+}
+
+/// Invoke `method` on `iomod`, writing its response to `sink`. IOmods which predate streaming are
+/// invoked through `agent` instead. Returns an RPC error if the IOmod did not deliver a response.
+async fn invoke_streaming(
+    iomod: iomod::Client,
+    agent: agent::Client,
+    method: &str,
+    input: &[u8],
+    sink: ResponseSink,
+) -> Result<Result<Vec<u8>, IoError>, capnp::Error> {
+    let state = sink.state.clone();
+    let mut request = iomod.invoke_streaming_request();
+    request.get().set_coordinates(method);
+    request.get().set_input(input);
+    request
+        .get()
+        .set_stream(capnp_rpc::new_client::<response_stream::Client, _>(sink));
+    match request.send().promise.await {
+        // the IOmod ends the response before `invokeStreaming` returns
+        Ok(_) => state.response.borrow_mut().take().ok_or_else(|| {
+            capnp::Error::failed("IOmod returned without ending its response".to_string())
+        }),
+        Err(why) if why.kind == capnp::ErrorKind::Unimplemented => {
+            invoke(agent, method, input).await
+        }
+        Err(why) => Err(why),
+    }
+}
+
+/// Invoke `method` on `agent`. Returns an RPC error if the IOmod did not deliver a response.
+async fn invoke(
+    agent: agent::Client,
+    method: &str,
+    input: &[u8],
+) -> Result<Result<Vec<u8>, IoError>, capnp::Error> {
+    let mut invoke = agent.invoke_request();
+    invoke.get().set_coordinates(method);
+    invoke.get().set_input(input);
+    let results = invoke.send().promise.await?;
+    let results = match results.get() {
+        Ok(results) => results,
+        Err(why) => return Ok(Err(malformed_response(why))),
+    };
+    // IOmods built before errors were carried by the protocol only ever set `result`
+    Ok(match results.has_error() {
+        true => Err(results.get_error().map_or_else(malformed_response, read_error)),
+        false => results
+            .get_result()
+            .map(Vec::from)
+            .map_err(malformed_response),
+    })
+}
+
+/// Receives the response of a call made with `invokeStreaming`, forwarding each chunk to the
+/// caller as an `IOMOD_CHUNK` message
+struct ResponseSink {
+    coords: String,
+    method: String,
+    responder: RegistryTx,
+    state: Rc<ResponseState>,
+}
+
+#[derive(Default)]
+struct ResponseState {
+    /// Set once a chunk has been written
+    written: Cell<bool>,
+    /// The outcome of the call, once the response has ended
+    response: RefCell<Option<Result<Vec<u8>, IoError>>>,
+}
+
+impl ResponseSink {
+    fn new(coords: &str, method: &str, responder: RegistryTx) -> Self {
+        Self {
+            coords: coords.to_string(),
+            method: method.to_string(),
+            responder,
+            state: Rc::new(ResponseState::default()),
+        }
+    }
+}
+
+impl response_stream::Server for ResponseSink {
+    fn write(
+        &mut self,
+        params: response_stream::WriteParams,
+        _results: response_stream::WriteResults,
+    ) -> Promise<(), capnp::Error> {
+        self.state.written.set(true);
+        let responder = self.responder.clone();
+        let iomod_coords = self.coords.clone();
+        let method_name = self.method.clone();
+
+        Promise::from_future(async move {
+            let chunk = RegistryChannelMessage {
+                iomod_coords,
+                method_name,
+                version: None,
+                payload_type: "IOMOD_CHUNK",
+                payload: Vec::from(params.get()?.get_chunk()?),
+                error: None,
+                responder: None,
+            };
+            // failing the write stops the IOmod from streaming to a caller which has gone away
+            responder
+                .send(chunk)
+                .await
+                .map_err(|_| capnp::Error::failed("the call was abandoned".to_string()))
+        })
+    }
+
+    fn end(
+        &mut self,
+        params: response_stream::EndParams,
+        _results: response_stream::EndResults,
+    ) -> Promise<(), capnp::Error> {
+        let response = match params.get().and_then(|params| params.get_response()) {
+            Ok(response) => read_response(response),
+            Err(why) => Err(malformed_response(why)),
+        };
+        *self.state.response.borrow_mut() = Some(response);
+        Promise::ok(())
+    }
+}
+
+/// Select the instance at `coords` satisfying `requirement` to dispatch a call to, skipping any
+/// already `tried`. Healthy instances are preferred, then the newest version, then those with
+/// the fewest outstanding calls; ties are broken round-robin.
+fn select_instance(
+    modules: &ModuleMap,
+    coords: &str,
+    requirement: Option<&VersionReq>,
+    tried: &[u64],
+) -> Option<(u64, Endpoint)> {
+    let mut modules = RefCell::borrow_mut(modules);
+    let instances = modules.get_mut(coords)?;
+    let (idx, _) = instances
+        .iter()
+        .enumerate()
+        .filter(|(_, registration)| !tried.contains(&registration.connection_id))
+        .filter(|(_, registration)| satisfies(registration, requirement))
+        .min_by_key(|(_, registration)| {
+            (
+                !registration.healthy,
+                Reverse(registration.version.clone()),
+                registration.outstanding,
+            )
+        })?;
+
+    // moving the selected instance to the back rotates it behind any it tied with
+    let mut registration = instances.remove(idx);
+    registration.outstanding += 1;
+    let selected = (registration.connection_id, registration.endpoint.clone());
+    instances.push(registration);
+    Some(selected)
+}
+
+/// True if the version of `registration` satisfies `requirement`
+fn satisfies(registration: &Registration, requirement: Option<&VersionReq>) -> bool {
+    match (requirement, &registration.version) {
+        (Some(requirement), Some(version)) => requirement.matches(version),
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+/// Record that a call dispatched to the instance at `coords` on `connection_id` has completed
+fn release_instance(modules: &ModuleMap, coords: &str, connection_id: u64) {
+    let mut modules = RefCell::borrow_mut(modules);
+    let registration = modules
+        .get_mut(coords)
+        .and_then(|instances| instances.iter_mut().find(|r| r.connection_id == connection_id));
+    if let Some(registration) = registration {
+        registration.outstanding = registration.outstanding.saturating_sub(1);
+    }
+}
+
+/// Ping the IOmod `iomod` registered on `connection_id`, and record whether it responded
+async fn ping(modules: ModuleMap, coords: String, connection_id: u64, iomod: iomod::Client) {
+    let ping = iomod.ping_request().send().promise;
+    let healthy = matches!(tokio::time::timeout(PING_TIMEOUT, ping).await, Ok(Ok(_)));
+
+    // the IOmod may have disconnected while the ping was in-flight
+    let mut modules = RefCell::borrow_mut(&modules);
+    let current = modules.get_mut(&coords).and_then(|instances| {
+        instances
+            .iter_mut()
+            .find(|r| r.connection_id == connection_id)
+    });
+    if let Some(current) = current {
+        if current.healthy && !healthy {
+            warn!(
+                "IOmod at {} on connection {} is unhealthy: it did not respond to ping",
+                coords, current.connection_id
+            );
+        } else if !current.healthy && healthy {
+            info!(
+                "IOmod at {} on connection {} is healthy",
+                coords, current.connection_id
+            );
+        }
+        current.healthy = healthy;
+    }
 }
 
 impl Registry {
-    pub fn new(modules: ModuleMap) -> Self {
-        Self { modules }
+    pub fn new(
+        modules: ModuleMap,
+        config: Arc<RegistryConfig>,
+        registered: Rc<RegisteredTx>,
+        connection_id: u64,
+    ) -> Self {
+        Self {
+            modules,
+            config,
+            registered,
+            connection_id,
+        }
+    }
+
+    /// Check that an IOmod registering at `coordinates` with `token` is allowed to
+    fn authorize(&self, coordinates: &str, token: &str) -> Result<(), capnp::Error> {
+        if let Some(expected) = &self.config.token {
+            if !constant_time_eq(expected.as_bytes(), token.as_bytes()) {
+                return Err(capnp::Error::failed(format!(
+                    "invalid registration token for IOmod at {}",
+                    coordinates
+                )));
+            }
+        }
+        if let Some(declared) = &self.config.declared_coordinates {
+            if !declared.contains(coordinates) {
+                return Err(capnp::Error::failed(format!(
+                    "{} is not a declared IOmod dependency",
+                    coordinates
+                )));
+            }
+        }
+        Ok(())
     }
 }
 
+/// Compare `a` and `b` in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 impl registry::Server for Registry {
     fn register(
         &mut self,
         params: registry::RegisterParams,
         mut _results: registry::RegisterResults,
     ) -> Promise<(), capnp::Error> {
-        let coordinates: String = String::from(params.get().unwrap().get_coordinates().unwrap());
-        let module: Rc<RefCell<iomod::Client>> =
-            Rc::new(RefCell::new(params.get().unwrap().get_iomod().unwrap()));
+        // the peer is not yet trusted, so malformed params fail the registration rather than
+        // the connection
+        let params = pry!(params.get());
+        let coordinates = String::from(pry!(params.get_coordinates()));
+        let token = params.get_token().unwrap_or("");
+        if let Err(why) = self.authorize(&coordinates, token) {
+            warn!("rejected IOmod registration: {}", why.description);
+            return Promise::err(why);
+        }
+        let version = match params.get_version() {
+            Ok("") | Err(_) => None,
+            Ok(version) => match Version::parse(version) {
+                Ok(version) => Some(version),
+                Err(why) => {
+                    return Promise::err(capnp::Error::failed(format!(
+                        "invalid version {} for IOmod at {}: {}",
+                        version, coordinates, why
+                    )))
+                }
+            },
+        };
+        let iomod = pry!(params.get_iomod());
+        add_registration(
+            &self.modules,
+            &self.registered,
+            coordinates,
+            Endpoint::rpc(iomod),
+            version,
+            self.connection_id,
+        );
 
-        let agent: agent::Client = capnp_rpc::new_client(Agent::new(module));
+        Promise::ok(())
+    }
+}
 
-        let modules = self.modules.clone();
-        let mut modules_ref = RefCell::borrow_mut(&modules);
-        modules_ref.entry(coordinates.clone()).or_insert(agent);
-        info!("registered IOmod at coordinates {}", coordinates.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Promise::ok(())
+    const COORDS: &str = "akkoro.std.http";
+
+    fn registration(connection_id: u64) -> Registration {
+        let (tx, _) = mpsc::channel(1);
+        Registration {
+            endpoint: Endpoint::rpc(capnp_rpc::new_client(Iomod::new(tx))),
+            version: None,
+            connection_id,
+            healthy: true,
+            outstanding: 0,
+        }
+    }
+
+    fn versioned(connection_id: u64, version: &str) -> Registration {
+        Registration {
+            version: Some(Version::parse(version).unwrap()),
+            ..registration(connection_id)
+        }
+    }
+
+    fn modules(registrations: Vec<Registration>) -> ModuleMap {
+        let mut map = HashMap::new();
+        map.insert(COORDS.to_string(), registrations);
+        Rc::new(RefCell::new(map))
+    }
+
+    fn select(modules: &ModuleMap, tried: &[u64]) -> Option<u64> {
+        select_instance(modules, COORDS, None, tried).map(|(id, _)| id)
+    }
+
+    fn select_matching(modules: &ModuleMap, requirement: &str) -> Option<u64> {
+        let requirement = VersionReq::parse(requirement).unwrap();
+        select_instance(modules, COORDS, Some(&requirement), &[]).map(|(id, _)| id)
+    }
+
+    fn outstanding(modules: &ModuleMap, connection_id: u64) -> usize {
+        RefCell::borrow(modules)[COORDS]
+            .iter()
+            .find(|r| r.connection_id == connection_id)
+            .unwrap()
+            .outstanding
+    }
+
+    #[test]
+    fn unknown_coordinates() {
+        let modules = modules(vec![registration(1)]);
+        assert_eq!(
+            select_instance(&modules, "akkoro.std.dns", None, &[]).map(|(id, _)| id),
+            None
+        );
+    }
+
+    #[test]
+    fn routes_to_newest_matching_version() {
+        let modules = modules(vec![
+            versioned(1, "1.0.0"),
+            versioned(2, "1.2.0"),
+            versioned(3, "2.0.0"),
+            registration(4),
+        ]);
+        assert_eq!(select(&modules, &[]), Some(3));
+        assert_eq!(select_matching(&modules, "^1"), Some(2));
+        assert_eq!(select_matching(&modules, "~1.0"), Some(1));
+        assert_eq!(select_matching(&modules, "^3"), None);
+    }
+
+    #[test]
+    fn unversioned_instances_only_serve_calls_without_a_requirement() {
+        let modules = modules(vec![registration(1)]);
+        assert_eq!(select_matching(&modules, "*"), None);
+        assert_eq!(select(&modules, &[]), Some(1));
+    }
+
+    #[test]
+    fn prefers_healthy_instances() {
+        // an unhealthy instance is passed over even for an older version
+        let mut unhealthy = versioned(1, "2.0.0");
+        unhealthy.healthy = false;
+        let modules = modules(vec![unhealthy, versioned(2, "1.0.0")]);
+        assert_eq!(select(&modules, &[]), Some(2));
+        assert_eq!(select(&modules, &[2]), Some(1));
+    }
+
+    #[test]
+    fn skips_tried_instances() {
+        let modules = modules(vec![registration(1), registration(2)]);
+        let first = select(&modules, &[]).unwrap();
+        let second = select(&modules, &[first]).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(select(&modules, &[first, second]), None);
+    }
+
+    #[test]
+    fn selects_least_outstanding() {
+        let modules = modules(vec![registration(1), registration(2), registration(3)]);
+        // with none completing, calls are spread evenly
+        let mut selected = (0..6)
+            .map(|_| select(&modules, &[]).unwrap())
+            .collect::<Vec<_>>();
+        selected.sort_unstable();
+        assert_eq!(selected, vec![1, 1, 2, 2, 3, 3]);
+
+        release_instance(&modules, COORDS, 2);
+        release_instance(&modules, COORDS, 2);
+        assert_eq!(outstanding(&modules, 2), 0);
+        assert_eq!(select(&modules, &[]), Some(2));
+        assert_eq!(outstanding(&modules, 2), 1);
+    }
+
+    #[test]
+    fn ties_are_broken_round_robin() {
+        let modules = modules(vec![registration(1), registration(2)]);
+        let first = select(&modules, &[]).unwrap();
+        release_instance(&modules, COORDS, first);
+        let second = select(&modules, &[]).unwrap();
+        release_instance(&modules, COORDS, second);
+        assert_ne!(first, second);
+        assert_eq!(select(&modules, &[]), Some(first));
+    }
+
+    #[test]
+    fn release_does_not_underflow() {
+        let modules = modules(vec![registration(1)]);
+        release_instance(&modules, COORDS, 1);
+        release_instance(&modules, COORDS, 9);
+        assert_eq!(outstanding(&modules, 1), 0);
     }
 }
@@ -0,0 +1,64 @@
+//! Descriptions of the methods an IOmod serves, and the JSON Schemas of their inputs and outputs.
+//!
+//! Schemas are generated by [`schemars`] from the `JsonSchema` implementation of a typed call's
+//! input and output, which IOmod authors derive alongside `Serialize` and `Deserialize`. The
+//! generator follows the type's serde attributes, so renamed and defaulted fields and every enum
+//! representation are described as they are serialized. The root type is described inline, and
+//! every other named type under `definitions`, referred to by `$ref`; recursive types refer to
+//! their own definition.
+
+use schemars::gen::SchemaGenerator;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub use schemars::JsonSchema;
+
+/// The methods served by an IOmod
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IomodDescription {
+    pub coordinates: String,
+    pub version: String,
+    pub methods: Vec<MethodDescription>,
+}
+
+impl IomodDescription {
+    /// The description as pretty-printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a description is always valid JSON")
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MethodDescription {
+    pub name: String,
+    /// The JSON Schema of the method's input. Untyped methods, which take and return raw bytes,
+    /// have none.
+    pub input: Option<Value>,
+    /// The JSON Schema of the method's output
+    pub output: Option<Value>,
+}
+
+/// The JSON Schema of `T`
+pub fn schema_for<T: JsonSchema>() -> Value {
+    let schema = SchemaGenerator::default().into_root_schema_for::<T>();
+    serde_json::to_value(schema).expect("a schema is always valid JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(JsonSchema)]
+    #[allow(dead_code)] // only its schema is used
+    struct Item {
+        id: u32,
+    }
+
+    #[test]
+    fn schema_for_is_a_root_schema() {
+        let schema = schema_for::<Item>();
+        assert!(schema["$schema"].is_string());
+        assert_eq!(schema["title"], "Item");
+        assert_eq!(schema["properties"]["id"]["type"], "integer");
+    }
+}
@@ -0,0 +1,293 @@
+//! Supervision of the IOmod processes launched by a runtime.
+//!
+//! Each IOmod is started from its `IomodManifest`, with the entrypoint, arguments, environment,
+//! and working directory of its `process` section. Output written by an IOmod to stdout or
+//! stderr is forwarded line-by-line to the runtime's tracing output. An IOmod which exits is
+//! restarted after a backoff, which doubles on each consecutive crash. Every IOmod is stopped
+//! when the supervisor is dropped or shut down, which runtimes do on `shutdown_signal`; each is
+//! sent SIGTERM, and killed if it has not exited within `STOP_GRACE`. An IOmod which declares
+//! `resources.memory_mb` has its data segment limited to that size.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::package::{IomodManifest, ProcessKind, Resources, CORE_VERSION};
+
+/// How long to wait before restarting an IOmod which has exited
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// The longest an IOmod which keeps crashing waits to be restarted
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long an IOmod must run before it is no longer considered to be crashing
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// How long an IOmod sent SIGTERM has to exit before it is killed
+const STOP_GRACE: Duration = Duration::from_secs(2);
+
+/// An IOmod process to be supervised
+#[derive(Clone, Debug)]
+pub struct IomodProcess {
+    pub coordinates: String,
+    pub version: String,
+    pub kind: ProcessKind,
+    pub entrypoint: PathBuf,
+    pub arguments: Vec<String>,
+    pub environment: HashMap<String, String>,
+    pub working_dir: Option<PathBuf>,
+    /// The methods declared by the IOmod's manifest, if it declares them
+    pub methods: Option<Vec<String>>,
+    pub resources: Option<Resources>,
+}
+
+impl IomodProcess {
+    /// The process described by `manifest`, whose entrypoint is relative to `dir`
+    pub fn from_manifest(manifest: &IomodManifest, dir: &Path) -> Self {
+        Self {
+            coordinates: manifest.iomod.coordinates.clone(),
+            version: manifest.iomod.version.clone(),
+            kind: manifest.process.kind,
+            entrypoint: dir.join(&manifest.process.entrypoint),
+            arguments: manifest.process.arguments.clone().unwrap_or_default(),
+            environment: manifest.process.environment.clone().unwrap_or_default(),
+            working_dir: manifest.process.working_dir.as_ref().map(|wd| dir.join(wd)),
+            methods: manifest.iomod.methods.clone(),
+            resources: manifest.resources.clone(),
+        }
+    }
+
+    /// The processes of the unpacked IOmods in `dir`; each subdirectory containing an
+    /// `iomod.toml` is one IOmod. IOmods whose manifest is invalid, or which require a newer
+    /// core, are skipped.
+    pub fn discover(dir: &Path) -> std::io::Result<Vec<Self>> {
+        let mut processes = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let manifest_path = path.join("iomod.toml");
+            if !path.is_dir() || !manifest_path.exists() {
+                continue;
+            }
+            let manifest = IomodManifest::load(&manifest_path).and_then(|manifest| {
+                manifest.validate()?;
+                manifest.check_core_version(CORE_VERSION)?;
+                Ok(manifest)
+            });
+            match manifest {
+                Ok(manifest) => processes.push(Self::from_manifest(&manifest, &path)),
+                Err(why) => error!("skipping IOmod in {:?}: {}", path, why),
+            }
+        }
+        Ok(processes)
+    }
+
+    pub fn label(&self) -> String {
+        format!("{}@{}", self.coordinates, self.version)
+    }
+
+    fn spawn(&self) -> std::io::Result<Child> {
+        let mut command = Command::new(&self.entrypoint);
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+        #[cfg(unix)]
+        if let Some(memory_mb) = self.resources.as_ref().and_then(|r| r.memory_mb) {
+            let limit = memory_mb.saturating_mul(1024 * 1024) as libc::rlim_t;
+            // SAFETY: setrlimit is async-signal-safe, and nothing is allocated after the fork
+            unsafe {
+                command.pre_exec(move || {
+                    let rlimit = libc::rlimit {
+                        rlim_cur: limit,
+                        rlim_max: limit,
+                    };
+                    match libc::setrlimit(libc::RLIMIT_DATA, &rlimit) {
+                        0 => Ok(()),
+                        _ => Err(std::io::Error::last_os_error()),
+                    }
+                });
+            }
+        }
+        command
+            .args(&self.arguments)
+            .envs(&self.environment)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+/// Starts IOmod processes and keeps them running until it is dropped
+pub struct IomodSupervisor {
+    shutdown: watch::Sender<bool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl IomodSupervisor {
+    /// Start supervising `processes` on a thread of their own. WASM IOmods are run by the
+    /// runtime rather than as processes, and are skipped.
+    pub fn spawn(processes: Vec<IomodProcess>) -> Self {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let thread = std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(async move {
+                let tasks = processes
+                    .into_iter()
+                    .filter(|process| match process.kind {
+                        ProcessKind::Native => true,
+                        ProcessKind::Wasm => {
+                            warn!("not supervising WASM IOmod {}", process.label());
+                            false
+                        }
+                    })
+                    .map(|process| tokio::spawn(supervise(process, shutdown_rx.clone())))
+                    .collect::<Vec<_>>();
+                for task in tasks {
+                    if let Err(why) = task.await {
+                        error!("IOmod supervisor task exited with error {:?}", why);
+                    }
+                }
+            });
+        });
+
+        Self {
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop every IOmod, and wait for them to exit
+    pub fn shutdown(&mut self) {
+        let _ = self.shutdown.send(true);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for IomodSupervisor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Wait for the runtime to be asked to stop, by SIGTERM or SIGINT
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = terminate.recv() => info!("received SIGTERM"),
+                _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+            },
+            Err(why) => {
+                error!("could not listen for SIGTERM: {}", why);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Run `process`, restarting it whenever it exits, until `shutdown` is signalled
+async fn supervise(process: IomodProcess, mut shutdown: watch::Receiver<bool>) {
+    let label = process.label();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        let started = Instant::now();
+        match process.spawn() {
+            Ok(mut child) => {
+                match &process.resources {
+                    Some(Resources { memory_mb, cpu }) => info!(
+                        "started IOmod {} (pid {:?}, memory limit {}, wants {} CPU)",
+                        label,
+                        child.id(),
+                        memory_mb.map_or("none".to_string(), |mb| format!("{} MB", mb)),
+                        cpu.map_or("unspecified".to_string(), |cpu| cpu.to_string()),
+                    ),
+                    None => info!("started IOmod {} (pid {:?})", label, child.id()),
+                }
+                if let Some(stdout) = child.stdout.take() {
+                    tokio::spawn(forward_output(label.clone(), stdout, false));
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    tokio::spawn(forward_output(label.clone(), stderr, true));
+                }
+
+                tokio::select! {
+                    status = child.wait() => match status {
+                        Ok(status) => warn!("IOmod {} exited with {}", label, status),
+                        Err(why) => error!("could not wait on IOmod {}: {}", label, why),
+                    },
+                    _ = shutdown.changed() => {
+                        stop(&mut child, &label).await;
+                        return;
+                    }
+                }
+            }
+            Err(why) => error!(
+                "could not start IOmod {} from {:?}: {}",
+                label, process.entrypoint, why
+            ),
+        }
+
+        // an IOmod which ran for a while before exiting has not been crash-looping
+        if started.elapsed() >= STABLE_AFTER {
+            backoff = INITIAL_BACKOFF;
+        }
+        info!("restarting IOmod {} in {:?}", label, backoff);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.changed() => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Ask `child` to exit with SIGTERM, and kill it if it has not exited within `STOP_GRACE`
+async fn stop(child: &mut Child, label: &str) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: kill has no memory-safety requirements; the child has not been reaped, so
+        // `pid` still refers to it
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0 {
+            match tokio::time::timeout(STOP_GRACE, child.wait()).await {
+                Ok(Ok(status)) => {
+                    info!("stopped IOmod {} ({})", label, status);
+                    return;
+                }
+                Ok(Err(why)) => error!("could not wait on IOmod {}: {}", label, why),
+                Err(_) => warn!(
+                    "IOmod {} did not exit within {:?} of SIGTERM, killing it",
+                    label, STOP_GRACE
+                ),
+            }
+        }
+    }
+    match child.kill().await {
+        Ok(()) => info!("stopped IOmod {}", label),
+        Err(why) => error!("could not stop IOmod {}: {}", label, why),
+    }
+}
+
+/// Forward each line of an IOmod's `output` to tracing
+async fn forward_output<R>(label: String, output: R, is_stderr: bool)
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match is_stderr {
+            true => warn!("[{}] {}", label, line),
+            false => info!("[{}] {}", label, line),
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Transports over which IOmods connect to the registry.
+//!
+//! The registry listens on `ASML_REGISTRY_ADDRESS`, which is either a TCP address such as
+//! `127.0.0.1:13555`, or the path of a Unix domain socket prefixed with `unix:`, such as
+//! `unix:/tmp/asml-registry.sock`. When it is not set the registry listens on the loopback
+//! interface only. IOmods started with the same environment connect to the same address.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::registry::RegistryError;
+
+/// The port the registry listens on when an address does not specify one
+pub const DEFAULT_REGISTRY_PORT: u16 = 13555;
+
+/// The read and write halves of a connection, as used by a capnp `VatNetwork`
+pub type ConnectionHalves = (
+    Box<dyn AsyncRead + Unpin>,
+    Box<dyn AsyncWrite + Unpin>,
+);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RegistryAddress {
+    /// A TCP socket address, as `host:port`
+    Tcp(String),
+    /// The path of a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl RegistryAddress {
+    /// The address set by `ASML_REGISTRY_ADDRESS`, if any
+    pub fn from_env() -> Result<Option<Self>, RegistryError> {
+        match std::env::var("ASML_REGISTRY_ADDRESS") {
+            Ok(address) => address.parse().map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The address the registry listens on, which is `127.0.0.1:13555` unless
+    /// `ASML_REGISTRY_ADDRESS` is set. The registry is only reachable from other hosts, or other
+    /// network namespaces, if it is set to listen there, e.g. on `0.0.0.0`.
+    pub fn for_registry() -> Result<Self, RegistryError> {
+        Ok(Self::from_env()?
+            .unwrap_or_else(|| Self::Tcp(format!("127.0.0.1:{}", DEFAULT_REGISTRY_PORT))))
+    }
+
+    /// The address an IOmod connects to, preferring `ASML_REGISTRY_ADDRESS` over `fallback`
+    pub fn for_iomod(fallback: &str) -> Result<Self, RegistryError> {
+        match Self::from_env()? {
+            Some(address) => Ok(address),
+            None => fallback.parse(),
+        }
+    }
+
+    /// Connect to the registry at this address
+    pub async fn connect(&self) -> io::Result<ConnectionHalves> {
+        match self {
+            Self::Tcp(address) => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+                Ok(split(stream))
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(split(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+}
+
+impl FromStr for RegistryAddress {
+    type Err = RegistryError;
+
+    /// Parse `unix:<path>`, or `[tcp:]host[:port]`
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let address = address.trim();
+        if let Some(path) = address.strip_prefix("unix:") {
+            let path = path.trim_start_matches("//");
+            return match path.is_empty() {
+                true => Err(RegistryError::new(format!(
+                    "missing socket path in registry address {}",
+                    address
+                ))),
+                false => Ok(Self::Unix(PathBuf::from(path))),
+            };
+        }
+
+        let host = address.strip_prefix("tcp:").unwrap_or(address);
+        let host = host.trim_start_matches("//");
+        if host.is_empty() {
+            return Err(RegistryError::new(format!(
+                "missing host in registry address {}",
+                address
+            )));
+        }
+        // a host without a port uses the default, e.g. `localhost` or `::1`
+        if host.parse::<SocketAddr>().is_ok() {
+            return Ok(Self::Tcp(host.to_string()));
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Self::Tcp(
+                SocketAddr::new(ip, DEFAULT_REGISTRY_PORT).to_string(),
+            ));
+        }
+        match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => Ok(Self::Tcp(host.to_string())),
+            _ => Ok(Self::Tcp(format!("{}:{}", host, DEFAULT_REGISTRY_PORT))),
+        }
+    }
+}
+
+impl fmt::Display for RegistryAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => write!(f, "tcp:{}", address),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A socket the registry accepts IOmod connections on
+pub enum RegistryListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl RegistryListener {
+    pub async fn bind(address: &RegistryAddress) -> io::Result<Self> {
+        match address {
+            RegistryAddress::Tcp(address) => Ok(Self::Tcp(TcpListener::bind(address).await?)),
+            #[cfg(unix)]
+            RegistryAddress::Unix(path) => {
+                remove_stale_socket(path).await?;
+                Ok(Self::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            RegistryAddress::Unix(_) => Err(unix_unsupported()),
+        }
+    }
+
+    /// Accept the next connection
+    pub async fn accept(&self) -> io::Result<ConnectionHalves> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, _) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                Ok(split(stream))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok(split(stream))
+            }
+        }
+    }
+}
+
+/// Remove a socket left behind at `path` by a runtime which has exited, as it would fail the
+/// bind. Anything else at `path`, including the socket of a registry which is still listening,
+/// is left in place and fails the bind instead.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(why) => return Err(why),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("another registry is listening on {}", path.display()),
+        )),
+        Err(why) if why.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(why) => Err(why),
+    }
+}
+
+fn split<S>(stream: S) -> ConnectionHalves
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    let (reader, writer) = tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+    (Box::new(reader), Box::new(writer))
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(address: &str) -> RegistryAddress {
+        address.parse().unwrap()
+    }
+
+    fn tcp(address: &str) -> RegistryAddress {
+        RegistryAddress::Tcp(address.to_string())
+    }
+
+    #[test]
+    fn unix_address() {
+        let expected = RegistryAddress::Unix(PathBuf::from("/tmp/asml.sock"));
+        assert_eq!(parse("unix:/tmp/asml.sock"), expected);
+        assert_eq!(parse("unix:///tmp/asml.sock"), expected);
+        assert_eq!(
+            parse("unix:asml.sock"),
+            RegistryAddress::Unix(PathBuf::from("asml.sock"))
+        );
+    }
+
+    #[test]
+    fn tcp_address_with_port() {
+        assert_eq!(parse("127.0.0.1:9000"), tcp("127.0.0.1:9000"));
+        assert_eq!(parse("tcp:127.0.0.1:9000"), tcp("127.0.0.1:9000"));
+        assert_eq!(parse("tcp://localhost:9000"), tcp("localhost:9000"));
+        assert_eq!(parse("[::1]:9000"), tcp("[::1]:9000"));
+    }
+
+    #[test]
+    fn tcp_address_without_port_uses_default() {
+        let port = DEFAULT_REGISTRY_PORT;
+        assert_eq!(parse("127.0.0.1"), tcp(&format!("127.0.0.1:{}", port)));
+        assert_eq!(parse("::1"), tcp(&format!("[::1]:{}", port)));
+        assert_eq!(parse("tcp:localhost"), tcp(&format!("localhost:{}", port)));
+        assert_eq!(
+            parse(" registry.local "),
+            tcp(&format!("registry.local:{}", port))
+        );
+    }
+
+    #[test]
+    fn missing_host_or_path_is_an_error() {
+        for address in &["", "tcp:", "tcp://", "unix:", "unix://"] {
+            assert!(
+                address.parse::<RegistryAddress>().is_err(),
+                "{:?} should not parse",
+                address
+            );
+        }
+    }
+}
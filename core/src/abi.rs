@@ -1,11 +1,13 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use wasmtime::{Caller, Val};
+use wasmtime::{Caller, Func, Val};
 
 use itertools::Itertools;
 
+use assemblylift_core_io_common::abi::AbiError;
+
 use crate::buffers::PagedWasmBuffer;
-use crate::wasm::{State, Wasmtime};
+use crate::wasm::{BufferElement, State, Wasmtime};
 
 pub trait RuntimeAbi<S: Clone + Send + Sized + 'static> {
     fn log(caller: Caller<'_, State<S>>, ptr: u32, len: u32);
@@ -23,14 +25,18 @@ where
     R: RuntimeAbi<S> + 'static,
     S: Clone + Send + Sized + 'static,
 {
-    if let Ok(method_path) = Wasmtime::<R, S>::ptr_to_string(&mut caller, name_ptr, name_len) {
-        if let Ok(method_input) = Wasmtime::<R, S>::ptr_to_bytes(&mut caller, input_ptr, input_len)
-        {
-            return invoke_io(caller, &*method_path, method_input);
-        }
+    let method_path = match Wasmtime::<R, S>::ptr_to_string(&mut caller, name_ptr, name_len) {
+        Ok(method_path) => method_path,
+        Err(err) => return err.code(),
+    };
+    let method_input = match Wasmtime::<R, S>::ptr_to_bytes(&mut caller, input_ptr, input_len) {
+        Ok(method_input) => method_input,
+        Err(err) => return err.code(),
+    };
+    match invoke_io(caller, &*method_path, method_input) {
+        Ok(ioid) => ioid as i32,
+        Err(err) => err.code(),
     }
-
-    -1i32 // error
 }
 
 pub fn asml_abi_io_poll<S>(mut caller: Caller<'_, State<S>>, id: u32) -> i32
@@ -38,7 +44,21 @@ where
     S: Clone + Send + Sized + 'static,
 {
     let state = caller.data_mut();
-    state.threader.clone().lock().unwrap().poll(id) as i32
+    match state.lock_threader().poll(state.invocation_id, id) {
+        Ok(ready) => ready as i32,
+        Err(err) => err.code(),
+    }
+}
+
+pub fn asml_abi_io_status<S>(mut caller: Caller<'_, State<S>>, id: u32) -> i32
+where
+    S: Clone + Send + Sized + 'static,
+{
+    let state = caller.data_mut();
+    match state.lock_threader().failed(state.invocation_id, id) {
+        Ok(failed) => failed as i32,
+        Err(err) => err.code(),
+    }
 }
 
 pub fn asml_abi_io_len<S>(mut caller: Caller<'_, State<S>>, id: u32) -> u32
@@ -47,11 +67,8 @@ where
 {
     let state = caller.data_mut();
     match state
-        .threader
-        .clone()
-        .lock()
-        .unwrap()
-        .get_io_memory_document(id)
+        .lock_threader()
+        .get_io_memory_document(state.invocation_id, id)
     {
         Some(doc) => doc.length as u32,
         None => 0,
@@ -62,41 +79,22 @@ pub fn asml_abi_io_load<S>(mut caller: Caller<'_, State<S>>, id: u32) -> i32
 where
     S: Clone + Send + Sized + 'static,
 {
-    let state = caller.data_mut();
-    let io_buffer_ptr = state.io_buffer_ptr.unwrap();
-
-    let mut ptr: Vec<Val> = vec![Val::I32(0)];
-    if let Err(_err) = io_buffer_ptr.call(&mut caller, &[], &mut ptr) {
-        // TODO log with info! when tracing is added
-        return -1;
-    }
-    let ptr = *&ptr[0].i32().unwrap();
+    let io_buffer_ptr = caller.data().io_buffer_ptr;
+    let memory_offset = match guest_ptr(&mut caller, io_buffer_ptr) {
+        Ok(offset) => offset,
+        Err(err) => return err.code(),
+    };
 
-    let memory_offset = ptr as usize;
     let data = {
         let state = caller.data_mut();
         state
-            .threader
-            .lock()
-            .unwrap()
-            .document_load(memory_offset, id)
-            .unwrap()
+            .lock_threader()
+            .document_load(state.invocation_id, memory_offset, id)
     };
-    let memory = caller
-        .get_export("memory")
-        .expect("could not find the default memory export named \"memory\"")
-        .into_memory()
-        .unwrap();
-    match data.len() > 0 {
-        true => {
-            let offset = data[0].0;
-            let buffer = data.iter().map(|e| e.1).collect_vec();
-            match memory.write(&mut caller, offset, &buffer) {
-                Ok(_) => 0,
-                Err(_err) => -1,
-            }
-        }
-        false => -1,
+    // an empty document loads successfully, but has nothing to write
+    match data.and_then(|data| write_buffer(&mut caller, &data).map(|_| data.len())) {
+        Ok(len) => len as i32,
+        Err(err) => err.code(),
     }
 }
 
@@ -104,43 +102,47 @@ pub fn asml_abi_io_next<S>(mut caller: Caller<'_, State<S>>) -> i32
 where
     S: Clone + Send + Sized + 'static,
 {
-    let ptr = {
-        let state = caller.data_mut();
-        let io_buffer_ptr = state.io_buffer_ptr.unwrap();
-
-        let mut ptr: Vec<Val> = vec![Val::I32(0)];
-        if let Err(_err) = io_buffer_ptr.call(&mut caller, &[], &mut ptr) {
-            // TODO log with info! when tracing is added
-            return -1;
-        }
-        *&ptr[0].i32().unwrap()
+    let io_buffer_ptr = caller.data().io_buffer_ptr;
+    let memory_offset = match guest_ptr(&mut caller, io_buffer_ptr) {
+        Ok(offset) => offset,
+        Err(err) => return err.code(),
     };
 
-    let memory_offset = ptr as usize;
     let data = {
         let state = caller.data_mut();
         state
-            .threader
-            .lock()
-            .unwrap()
-            .document_next(memory_offset)
-            .unwrap()
+            .lock_threader()
+            .document_next(state.invocation_id, memory_offset)
     };
-    let memory = caller
-        .get_export("memory")
-        .expect("could not find the default memory export named \"memory\"")
-        .into_memory()
-        .unwrap();
-    match data.len() > 0 {
-        true => {
-            let offset = data[0].0;
-            let buffer = data.iter().map(|e| e.1).collect_vec();
-            match memory.write(&mut caller, offset, &buffer) {
-                Ok(_) => 0,
-                Err(_err) => -1,
-            }
-        }
-        false => -1,
+    match data {
+        Ok(data) if data.len() > 0 => match write_buffer(&mut caller, &data) {
+            Ok(_) => data.len() as i32,
+            Err(err) => err.code(),
+        },
+        Ok(_) => AbiError::EndOfBuffer.code(),
+        Err(err) => err.code(),
+    }
+}
+
+pub fn asml_abi_io_free<S>(mut caller: Caller<'_, State<S>>, id: u32) -> i32
+where
+    S: Clone + Send + Sized + 'static,
+{
+    let state = caller.data_mut();
+    match state.lock_threader().free(state.invocation_id, id) {
+        true => 0,
+        false => AbiError::UnknownIoid.code(),
+    }
+}
+
+pub fn asml_abi_io_cancel<S>(mut caller: Caller<'_, State<S>>, id: u32) -> i32
+where
+    S: Clone + Send + Sized + 'static,
+{
+    let state = caller.data_mut();
+    match state.lock_threader().cancel(state.invocation_id, id) {
+        true => 0,
+        false => AbiError::UnknownIoid.code(),
     }
 }
 
@@ -157,37 +159,22 @@ pub fn asml_abi_input_start<S>(mut caller: Caller<'_, State<S>>) -> i32
 where
     S: Clone + Send + Sized + 'static,
 {
-    let state = caller.data_mut();
-
-    let mut ptr: Vec<Val> = vec![Val::I32(0)];
-    if let Err(_err) = state
-        .function_input_buffer_ptr
-        .unwrap()
-        .call(&mut caller, &[], &mut ptr)
-    {
-        // TODO log with info! when tracing is added
-        return -1;
-    }
-    let ptr = *&ptr[0].i32().unwrap();
+    let function_input_buffer_ptr = caller.data().function_input_buffer_ptr;
+    let offset = match guest_ptr(&mut caller, function_input_buffer_ptr) {
+        Ok(offset) => offset,
+        Err(err) => return err.code(),
+    };
 
-    let offset = ptr as usize;
     let data = {
         let state = caller.data_mut();
         state.function_input_buffer.first(0, offset)
     };
-    let memory = caller
-        .get_export("memory")
-        .expect("could not find the default memory export named \"memory\"")
-        .into_memory()
-        .unwrap();
     match data.len() > 0 {
-        true => {
-            let offset = data[0].0;
-            let buffer = data.iter().map(|e| e.1).collect_vec();
-            memory.write(&mut caller, offset, &buffer).expect("");
-            0
-        }
-        false => -1,
+        true => match write_buffer(&mut caller, &data) {
+            Ok(_) => 0,
+            Err(err) => err.code(),
+        },
+        false => AbiError::EndOfBuffer.code(),
     }
 }
 
@@ -195,37 +182,22 @@ pub fn asml_abi_input_next<S>(mut caller: Caller<'_, State<S>>) -> i32
 where
     S: Clone + Send + Sized + 'static,
 {
-    let state = caller.data_mut();
-
-    let mut ptr: Vec<Val> = vec![Val::I32(0)];
-    if let Err(_err) = state
-        .function_input_buffer_ptr
-        .unwrap()
-        .call(&mut caller, &[], &mut ptr)
-    {
-        // TODO log with info! when tracing is added
-        return -1;
-    }
-    let ptr = *&ptr[0].i32().unwrap();
+    let function_input_buffer_ptr = caller.data().function_input_buffer_ptr;
+    let offset = match guest_ptr(&mut caller, function_input_buffer_ptr) {
+        Ok(offset) => offset,
+        Err(err) => return err.code(),
+    };
 
-    let offset = ptr as usize;
     let data = {
         let state = caller.data_mut();
         state.function_input_buffer.next(offset)
     };
-    let memory = caller
-        .get_export("memory")
-        .expect("could not find the default memory export named \"memory\"")
-        .into_memory()
-        .unwrap();
     match data.len() > 0 {
-        true => {
-            let offset = data[0].0;
-            let buffer = data.iter().map(|e| e.1).collect_vec();
-            memory.write(&mut caller, offset, &buffer).expect("");
-            0
-        }
-        false => -1,
+        true => match write_buffer(&mut caller, &data) {
+            Ok(_) => 0,
+            Err(err) => err.code(),
+        },
+        false => AbiError::EndOfBuffer.code(),
     }
 }
 
@@ -239,26 +211,60 @@ where
 
 #[inline(always)]
 /// Invoke an IOmod call at coordinates `method_path` with input `method_input`
-fn invoke_io<S>(caller: Caller<'_, State<S>>, method_path: &str, method_input: Vec<u8>) -> i32
+fn invoke_io<S>(
+    caller: Caller<'_, State<S>>,
+    method_path: &str,
+    method_input: Vec<u8>,
+) -> Result<u32, AbiError>
 where
     S: Clone + Send + Sized + 'static,
 {
-    let ioid = caller
-        .data()
-        .threader
-        .clone()
-        .lock()
-        .unwrap()
-        .next_ioid()
-        .expect("unable to get a new IO ID");
+    let state = caller.data();
+    let mut threader = state.lock_threader();
+    let ioid = threader
+        .next_ioid(state.invocation_id)
+        .ok_or(AbiError::Unknown)?;
+    if let Err(err) = threader.invoke(state.invocation_id, method_path, method_input, ioid) {
+        // the call was never made, so its IOID is released rather than left pending
+        threader.free(state.invocation_id, ioid);
+        return Err(err);
+    }
 
-    caller
-        .data()
-        .threader
-        .clone()
-        .lock()
-        .unwrap()
-        .invoke(method_path, method_input, ioid);
+    Ok(ioid)
+}
+
+/// Call the guest function `get_ptr`, which returns a pointer to one of the guest's buffers
+fn guest_ptr<S>(caller: &mut Caller<'_, State<S>>, get_ptr: Option<Func>) -> Result<usize, AbiError>
+where
+    S: Clone + Send + Sized + 'static,
+{
+    let get_ptr = get_ptr.ok_or(AbiError::MissingExport)?;
+    let mut ptr: Vec<Val> = vec![Val::I32(0)];
+    if let Err(_err) = get_ptr.call(&mut *caller, &[], &mut ptr) {
+        // TODO log with info! when tracing is added
+        return Err(AbiError::Unknown);
+    }
+    match ptr[0].i32() {
+        Some(ptr) => Ok(ptr as usize),
+        None => Err(AbiError::MissingExport),
+    }
+}
 
-    ioid as i32
+/// Write a page of buffer elements into guest memory
+fn write_buffer<S>(caller: &mut Caller<'_, State<S>>, data: &[BufferElement]) -> Result<(), AbiError>
+where
+    S: Clone + Send + Sized + 'static,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or(AbiError::MissingExport)?;
+    let offset = data[0].0;
+    let buffer = data.iter().map(|e| e.1).collect_vec();
+    memory
+        .write(&mut *caller, offset, &buffer)
+        .map_err(|_| AbiError::InvalidPointer)
 }
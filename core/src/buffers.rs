@@ -84,8 +84,44 @@ impl IoBuffer {
     }
 
     pub fn set(&mut self, ioid: usize, bytes: Vec<u8>) -> usize {
-        self.buffers.insert(ioid, bytes.clone());
-        bytes.len()
+        let len = bytes.len();
+        self.buffers.insert(ioid, bytes);
+        len
+    }
+
+    /// Append `bytes` to the buffer for `ioid`, returning the number of bytes added
+    pub fn append(&mut self, ioid: usize, bytes: Vec<u8>) -> usize {
+        let len = bytes.len();
+        match self.buffers.get_mut(&ioid) {
+            Some(buffer) => buffer.extend_from_slice(&bytes),
+            None => {
+                self.buffers.insert(ioid, bytes);
+            }
+        }
+        len
+    }
+
+    /// True if every byte of page `page_idx` of the buffer for `ioid` is present
+    pub fn page_complete(&self, ioid: usize, page_idx: usize) -> bool {
+        match self.buffers.get(&ioid) {
+            Some(buffer) => buffer.len() >= (page_idx + 1) * IO_BUFFER_SIZE_BYTES,
+            None => false,
+        }
+    }
+
+    /// The buffer being paged into guest memory, and the index of the page last loaded from it
+    pub fn active_page(&self) -> Option<(usize, usize)> {
+        let page_idx = self.page_indices.get(&self.active_buffer)?;
+        Some((self.active_buffer, *page_idx))
+    }
+
+    /// Remove the buffer for `ioid`, returning the number of bytes released
+    pub fn free(&mut self, ioid: usize) -> usize {
+        self.page_indices.remove(&ioid);
+        match self.buffers.remove(&ioid) {
+            Some(buffer) => buffer.len(),
+            None => 0,
+        }
     }
 }
 
@@ -95,7 +131,10 @@ impl PagedWasmBuffer for IoBuffer {
 
         self.active_buffer = buffer_id;
         self.page_indices.insert(self.active_buffer, 0usize);
-        let buffer = self.buffers.get(&self.active_buffer).unwrap();
+        let buffer = match self.buffers.get(&self.active_buffer) {
+            Some(buffer) => buffer,
+            None => return Vec::new(),
+        };
         let end = min(IO_BUFFER_SIZE_BYTES, buffer.len());
         let mut out: Vec<BufferElement> = Vec::with_capacity(end);
 
@@ -112,9 +151,18 @@ impl PagedWasmBuffer for IoBuffer {
     fn next(&mut self, offset: usize) -> Vec<BufferElement> {
         use std::cmp::min;
 
-        let buffer = self.buffers.get(&self.active_buffer).unwrap();
-        let page_idx = self.page_indices.get(&self.active_buffer).unwrap() + 1;
+        // the active buffer may have been freed
+        let (buffer, page_idx) = match (
+            self.buffers.get(&self.active_buffer),
+            self.page_indices.get(&self.active_buffer),
+        ) {
+            (Some(buffer), Some(page_idx)) => (buffer, page_idx + 1),
+            _ => return Vec::new(),
+        };
         let page_offset = page_idx * IO_BUFFER_SIZE_BYTES;
+        if page_offset >= buffer.len() {
+            return Vec::new();
+        }
         let end = min(page_offset + IO_BUFFER_SIZE_BYTES, buffer.len());
         let mut out: Vec<BufferElement> = Vec::with_capacity(end);
 
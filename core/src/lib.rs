@@ -2,5 +2,7 @@ pub use wasmtime::{AsContextMut, Caller};
 
 pub mod abi;
 pub mod buffers;
+pub mod limits;
 pub mod threader;
 pub mod wasm;
+pub mod wasm_iomod;
@@ -0,0 +1,309 @@
+//! Resource limits applied to WASM function instances.
+//! A `FunctionLimiter` is installed as the `ResourceLimiter` of each function `Store`, so that
+//! a misbehaving function fails its own invocation rather than exhausting the host.
+//! `IoLimits` are enforced by the `Threader`, so that a misbehaving function cannot flood an
+//! IOmod (and whatever is downstream of it) with calls.
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use tracing::warn;
+use wasmtime::ResourceLimiter;
+
+use crate::threader::parse_iomod_settings;
+
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+/// The default function limits, with the memory limit set from `size_mb` in the service manifest
+pub static FUNCTION_LIMITS: Lazy<FunctionLimits> = Lazy::new(|| FunctionLimits {
+    memory_bytes: std::env::var("ASML_FUNCTION_SIZE_MB")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .map(|mb| mb * BYTES_PER_MB),
+    table_elements: std::env::var("ASML_FUNCTION_MAX_TABLE_ELEMENTS")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok()),
+    ..Default::default()
+});
+
+#[derive(Clone, Debug)]
+/// Caps on the memory, tables, and instances a function may create
+pub struct FunctionLimits {
+    /// Maximum size in bytes of any linear memory
+    pub memory_bytes: Option<usize>,
+    /// Maximum number of elements in any table
+    pub table_elements: Option<u32>,
+    /// Maximum number of instances in a function store
+    pub instances: usize,
+    /// Maximum number of tables in a function store
+    pub tables: usize,
+    /// Maximum number of linear memories in a function store
+    pub memories: usize,
+}
+
+impl FunctionLimits {
+    /// Build limits capping linear memory at `size_mb` megabytes
+    pub fn from_size_mb(size_mb: usize) -> Self {
+        Self {
+            memory_bytes: Some(size_mb * BYTES_PER_MB),
+            ..FUNCTION_LIMITS.clone()
+        }
+    }
+}
+
+impl Default for FunctionLimits {
+    fn default() -> Self {
+        Self {
+            memory_bytes: None,
+            table_elements: None,
+            instances: 1,
+            tables: 1,
+            memories: 1,
+        }
+    }
+}
+
+/// A `ResourceLimiter` enforcing `FunctionLimits`, which remembers which limit (if any) was hit
+pub struct FunctionLimiter {
+    limits: FunctionLimits,
+    memory_limit_exceeded: bool,
+    table_limit_exceeded: bool,
+}
+
+impl FunctionLimiter {
+    pub fn new(limits: FunctionLimits) -> Self {
+        Self {
+            limits,
+            memory_limit_exceeded: false,
+            table_limit_exceeded: false,
+        }
+    }
+
+    pub fn limits(&self) -> &FunctionLimits {
+        &self.limits
+    }
+
+    /// True if the function attempted to grow a memory beyond `memory_bytes`
+    pub fn memory_limit_exceeded(&self) -> bool {
+        self.memory_limit_exceeded
+    }
+
+    /// True if the function attempted to grow a table beyond `table_elements`
+    pub fn table_limit_exceeded(&self) -> bool {
+        self.table_limit_exceeded
+    }
+}
+
+impl ResourceLimiter for FunctionLimiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        match self.limits.memory_bytes {
+            Some(limit) if desired > limit => {
+                self.memory_limit_exceeded = true;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        match self.limits.table_elements {
+            Some(limit) if desired > limit => {
+                self.table_limit_exceeded = true;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.instances
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.tables
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.memories
+    }
+}
+
+/// The default IOmod call limits, set from `ASML_IOMOD_MAX_CALLS`, `ASML_IOMOD_MAX_IN_FLIGHT`,
+/// and `ASML_IOMOD_RATE_LIMITS`
+pub static IO_LIMITS: Lazy<IoLimits> = Lazy::new(|| IoLimits {
+    calls: std::env::var("ASML_IOMOD_MAX_CALLS")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok()),
+    in_flight: std::env::var("ASML_IOMOD_MAX_IN_FLIGHT")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok()),
+    rates: parse_iomod_settings("ASML_IOMOD_RATE_LIMITS")
+        .into_iter()
+        .filter_map(|(coords, rate)| match rate.parse::<Rate>() {
+            Ok(rate) => Some((coords, rate)),
+            Err(why) => {
+                // an IOmod whose rate cannot be parsed is left unlimited, so make that visible
+                warn!("ignoring ASML_IOMOD_RATE_LIMITS entry for {}: {}", coords, why);
+                None
+            }
+        })
+        .collect(),
+});
+
+#[derive(Clone, Debug, Default)]
+/// Caps on the IOmod calls functions may make
+pub struct IoLimits {
+    /// Maximum number of calls a single invocation may make
+    pub calls: Option<u32>,
+    /// Maximum number of calls a single invocation may have in-flight at once
+    pub in_flight: Option<u32>,
+    /// The rate at which calls may be made to each IOmod across all invocations, keyed by
+    /// IOmod coordinates
+    pub rates: HashMap<String, Rate>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+/// A token-bucket rate, refilling `per_second` tokens each second up to a capacity of `burst`
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl std::str::FromStr for Rate {
+    type Err = String;
+
+    /// Parse `per_second`, or `per_second:burst`. The burst defaults to one second's worth of calls.
+    fn from_str(rate: &str) -> Result<Self, Self::Err> {
+        let parse = |n: &str| match n.trim().parse::<f64>() {
+            Ok(n) if n > 0f64 && n.is_finite() => Ok(n),
+            _ => Err(format!("invalid rate {}", rate)),
+        };
+        let (per_second, burst) = match rate.split_once(':') {
+            Some((per_second, burst)) => (parse(per_second)?, parse(burst)?),
+            None => {
+                let per_second = parse(rate)?;
+                (per_second, per_second.max(1f64))
+            }
+        };
+        Ok(Self { per_second, burst })
+    }
+}
+
+/// A token bucket enforcing a `Rate`
+pub struct RateLimiter {
+    rate: Rate,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket, returning false if it is empty
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.refilled_at = now;
+        match self.tokens >= 1f64 {
+            true => {
+                self.tokens -= 1f64;
+                true
+            }
+            false => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn rate_burst_defaults_to_one_second() {
+        let rate: Rate = "5".parse().unwrap();
+        assert_eq!(
+            rate,
+            Rate {
+                per_second: 5f64,
+                burst: 5f64
+            }
+        );
+
+        let rate: Rate = "0.5".parse().unwrap();
+        assert_eq!(
+            rate,
+            Rate {
+                per_second: 0.5,
+                burst: 1f64
+            }
+        );
+    }
+
+    #[test]
+    fn rate_with_burst() {
+        let rate: Rate = " 2 : 10 ".parse().unwrap();
+        assert_eq!(
+            rate,
+            Rate {
+                per_second: 2f64,
+                burst: 10f64
+            }
+        );
+    }
+
+    #[test]
+    fn rate_rejects_invalid() {
+        for rate in &["", "0", "-1", "1:0", "1:-2", "inf", "NaN", "fast", "1:2:3"] {
+            assert!(rate.parse::<Rate>().is_err(), "{} should not parse", rate);
+        }
+    }
+
+    #[test]
+    fn limiter_starts_full_and_empties() {
+        let mut limiter = RateLimiter::new(Rate {
+            per_second: 1f64,
+            burst: 3f64,
+        });
+        let now = limiter.refilled_at;
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+    }
+
+    #[test]
+    fn limiter_refills_up_to_burst() {
+        let mut limiter = RateLimiter::new(Rate {
+            per_second: 2f64,
+            burst: 2f64,
+        });
+        let start = limiter.refilled_at;
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+
+        // half a second refills one token at 2/s
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+
+        // a long idle period refills no more than the burst
+        let much_later = later + Duration::from_secs(60);
+        assert!(limiter.try_acquire_at(much_later));
+        assert!(limiter.try_acquire_at(much_later));
+        assert!(!limiter.try_acquire_at(much_later));
+    }
+}
@@ -2,21 +2,80 @@
 //! "Threader" is the interface between the Wasmer runtime and the IOmod RPC network.
 //! See [core-threader doc](../../docs/core-threader.md) for more details.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
 
+use once_cell::sync::Lazy;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
 
+use assemblylift_core_io_common::abi::AbiError;
+use assemblylift_core_io_common::iomod::{IoError, IoErrorKind};
 use assemblylift_core_iomod::registry::{RegistryChannelMessage, RegistryTx};
 
 use crate::buffers::{IoBuffer, PagedWasmBuffer};
+use crate::limits::{IoLimits, RateLimiter, IO_LIMITS};
 use crate::wasm::BufferElement;
 
+/// The per-call timeout of each IOmod dependency, keyed by IOmod coordinates.
+/// Set from `timeout_seconds` of each dependency in the service manifest.
+pub static IOMOD_TIMEOUTS: Lazy<HashMap<String, Duration>> = Lazy::new(|| {
+    parse_iomod_settings("ASML_IOMOD_TIMEOUTS")
+        .into_iter()
+        .filter_map(|(coords, seconds)| {
+            Some((coords, Duration::from_secs(seconds.parse::<u64>().ok()?)))
+        })
+        .collect()
+});
+
+/// The version of each IOmod dependency, keyed by IOmod coordinates.
+/// Set from `version` of each dependency in the service manifest, and used as the version
+/// requirement of calls which do not specify one.
+pub static IOMOD_VERSIONS: Lazy<HashMap<String, String>> =
+    Lazy::new(|| parse_iomod_settings("ASML_IOMOD_VERSIONS"));
+
+/// The IOmod calls the function may make. Each entry is either IOmod coordinates, permitting
+/// every method of that IOmod, or the path of a single method. Set from the dependencies in the
+/// service manifest; every call is permitted if it is not set.
+pub static IOMOD_CAPABILITIES: Lazy<Option<HashSet<String>>> = Lazy::new(|| {
+    std::env::var("ASML_IOMOD_CAPABILITIES").ok().map(|capabilities| {
+        capabilities
+            .split(',')
+            .map(|capability| capability.trim())
+            .filter(|capability| !capability.is_empty())
+            .map(String::from)
+            .collect()
+    })
+});
+
 pub type IoId = u32;
+pub type InvocationId = u64;
+
+/// IO memory for each in-flight invocation, keyed by invocation id
+type IoMemoryMap = Arc<Mutex<HashMap<InvocationId, IoMemory>>>;
+
+#[derive(Clone, Debug, Default)]
+/// Counts of the IOmod calls made by functions, and of the calls rejected by `IoLimits`
+pub struct IoMetrics {
+    /// Calls invoked
+    pub calls: u64,
+    /// Calls rejected because their invocation had used its quota
+    pub quota_exceeded: u64,
+    /// Calls rejected because their invocation had too many calls in-flight
+    pub in_flight_exceeded: u64,
+    /// Calls rejected by the rate limit of their IOmod, keyed by IOmod coordinates
+    pub rate_limited: HashMap<String, u64>,
+}
 
 pub struct Threader<S> {
-    io_memory: Arc<Mutex<IoMemory>>,
+    io_memory: IoMemoryMap,
+    next_invocation_id: InvocationId,
+    limits: IoLimits,
+    rate_limiters: HashMap<String, RateLimiter>,
+    metrics: IoMetrics,
     registry_tx: RegistryTx,
     runtime: tokio::runtime::Runtime,
     _phantom: std::marker::PhantomData<S>,
@@ -26,120 +85,293 @@ impl<S> Threader<S>
 where
     S: Clone + Send + Sized + 'static,
 {
-    /// Create a new Threader instance with the provided sender `tx`
+    /// Create a new Threader instance with the provided sender `tx`.
+    /// A single Threader is intended to serve every invocation made by the process.
     pub fn new(tx: RegistryTx) -> Self {
         Threader {
-            io_memory: Arc::new(Mutex::new(IoMemory::new())),
+            io_memory: Arc::new(Mutex::new(HashMap::new())),
+            next_invocation_id: 1,
+            limits: IO_LIMITS.clone(),
+            rate_limiters: HashMap::new(),
+            metrics: IoMetrics::default(),
             registry_tx: tx,
             runtime: tokio::runtime::Runtime::new().unwrap(),
-            _phantom: std::marker::PhantomData::default(),
+            _phantom: std::marker::PhantomData,
         }
     }
 
+    /// Allocate IO memory for a new invocation, returning the id which scopes its IOmod calls
+    pub fn begin_invocation(&mut self) -> InvocationId {
+        let invocation_id = self.next_invocation_id;
+        self.next_invocation_id += 1;
+        lock_io_memory(&self.io_memory).insert(invocation_id, IoMemory::new());
+        invocation_id
+    }
+
+    /// Release the IO memory of `invocation_id`, and abandon any of its calls which are still
+    /// in-flight.
+    pub fn end_invocation(&mut self, invocation_id: InvocationId) {
+        lock_io_memory(&self.io_memory).remove(&invocation_id);
+    }
+
     /// Issue an unused IOID for a new IOmod call
-    pub fn next_ioid(&mut self) -> Option<IoId> {
-        match self.io_memory.clone().lock() {
-            Ok(mut memory) => memory.next_id(),
-            Err(_) => None,
-        }
+    pub fn next_ioid(&mut self, invocation_id: InvocationId) -> Option<IoId> {
+        lock_io_memory(&self.io_memory)
+            .get_mut(&invocation_id)?
+            .next_id()
     }
 
     /// Fetch the memory document associated with `ioid`
-    pub fn get_io_memory_document(&mut self, ioid: IoId) -> Option<IoMemoryDocument> {
-        match self.io_memory.clone().lock() {
-            Ok(memory) => match memory.document_map.get(&ioid) {
-                Some(doc) => Some(doc.clone()),
-                None => None,
-            },
-            Err(_) => None,
-        }
+    pub fn get_io_memory_document(
+        &mut self,
+        invocation_id: InvocationId,
+        ioid: IoId,
+    ) -> Option<IoMemoryDocument> {
+        lock_io_memory(&self.io_memory)
+            .get(&invocation_id)?
+            .document_map
+            .get(&ioid)
+            .cloned()
     }
 
-    /// Load the memory document associated with `ioid` into the guest IO memory
+    /// Load the memory document associated with `ioid` into the guest IO memory.
+    /// The document of a call which is still streaming its response is loaded once its first page
+    /// has arrived in full; until then this returns `NotReady`.
     pub fn document_load(
         &mut self,
+        invocation_id: InvocationId,
         memory_offset: usize,
         ioid: IoId,
-    ) -> anyhow::Result<Vec<BufferElement>> {
-        let doc = self.get_io_memory_document(ioid).unwrap();
-        let data = self.io_memory
-            .lock()
-            .unwrap()
-            .buffer
-            .first(doc.start, memory_offset);
-        Ok(data)
-    }
-
-    /// Advance the guest IO memory to the next page
-    pub fn document_next(&mut self, memory_offset: usize) -> anyhow::Result<Vec<BufferElement>> {
-        let data = self.io_memory
-            .lock()
-            .unwrap()
-            .buffer
-            .next(memory_offset);
-        Ok(data)
+    ) -> Result<Vec<BufferElement>, AbiError> {
+        let mut memory = lock_io_memory(&self.io_memory);
+        let memory = memory.get_mut(&invocation_id).ok_or(AbiError::Unknown)?;
+        if memory.is_pending(ioid) && !memory.buffer.page_complete(ioid as usize, 0) {
+            return Err(AbiError::NotReady);
+        }
+        let doc = memory.document_map.get(&ioid).ok_or(AbiError::UnknownIoid)?;
+        Ok(memory.buffer.first(doc.start, memory_offset))
     }
 
-    /// Poll the runtime for the completion status of call associated with `ioid`
-    pub fn poll(&mut self, ioid: IoId) -> bool {
-        match self.io_memory.clone().lock() {
-            Ok(memory) => {
-                match memory.poll(ioid) {
-                    true => {
-                        // At this point, the document "contents" have already been written to the WASM buffer
-                        //    and are read on the guest side immediately after poll() exits.
-                        // We can free the host-side memory structure here.
-
-                        //                        memory.free(ioid);
-                        true
-                    }
-                    false => false,
-                }
+    /// Advance the guest IO memory to the next page. Returns `NotReady` if the call is still
+    /// streaming its response, and the next page has not arrived in full.
+    pub fn document_next(
+        &mut self,
+        invocation_id: InvocationId,
+        memory_offset: usize,
+    ) -> Result<Vec<BufferElement>, AbiError> {
+        let mut memory = lock_io_memory(&self.io_memory);
+        let memory = memory.get_mut(&invocation_id).ok_or(AbiError::Unknown)?;
+        if let Some((ioid, page_idx)) = memory.buffer.active_page() {
+            if memory.is_pending(ioid as IoId) && !memory.buffer.page_complete(ioid, page_idx + 1)
+            {
+                return Err(AbiError::NotReady);
             }
-            Err(_) => false,
         }
+        Ok(memory.buffer.next(memory_offset))
+    }
+
+    /// Poll the runtime for the completion status of call associated with `ioid`
+    pub fn poll(&mut self, invocation_id: InvocationId, ioid: IoId) -> Result<bool, AbiError> {
+        match lock_io_memory(&self.io_memory).get(&invocation_id) {
+            Some(memory) => memory.poll(ioid),
+            None => Err(AbiError::Unknown),
+        }
+    }
+
+    /// Check whether the call associated with `ioid` failed, in which case its document is an
+    /// `IoError` rather than the response of the IOmod
+    pub fn failed(&mut self, invocation_id: InvocationId, ioid: IoId) -> Result<bool, AbiError> {
+        match lock_io_memory(&self.io_memory).get(&invocation_id) {
+            Some(memory) => memory.failed(ioid),
+            None => Err(AbiError::Unknown),
+        }
+    }
+
+    /// Abandon the call associated with `ioid`, releasing its memory document if it has already
+    /// completed. Returns false if there is no such call.
+    pub fn cancel(&mut self, invocation_id: InvocationId, ioid: IoId) -> bool {
+        match lock_io_memory(&self.io_memory).get_mut(&invocation_id) {
+            Some(memory) => memory.cancel(ioid),
+            None => false,
+        }
+    }
+
+    /// Release the memory document associated with `ioid`, once the guest has finished reading it.
+    /// A call which has not yet responded is abandoned. Returns false if there is no such call.
+    pub fn free(&mut self, invocation_id: InvocationId, ioid: IoId) -> bool {
+        match lock_io_memory(&self.io_memory).get_mut(&invocation_id) {
+            Some(memory) => memory.free(ioid),
+            None => false,
+        }
+    }
+
+    /// The number of bytes of IO memory held by `invocation_id`
+    pub fn io_memory_held(&self, invocation_id: InvocationId) -> usize {
+        match lock_io_memory(&self.io_memory).get(&invocation_id) {
+            Some(memory) => memory.bytes_held,
+            None => 0,
+        }
+    }
+
+    /// The number of bytes of IO memory held by each in-flight invocation
+    pub fn io_memory_usage(&self) -> HashMap<InvocationId, usize> {
+        lock_io_memory(&self.io_memory)
+            .iter()
+            .map(|(invocation_id, memory)| (*invocation_id, memory.bytes_held))
+            .collect()
     }
 
     /// Invoke the IOmod call at `method_path` with `method_input`, and assign it id `ioid`.
     /// A task is spawned on the Threader's tokio runtime which runs until the IOmod call responds.
-    pub fn invoke(&mut self, method_path: &str, method_input: Vec<u8>, ioid: IoId) {
+    /// The chunks of a streamed response are appended to the call's document as they arrive.
+    /// The method path must be of the form `org.namespace.name.method`.
+    pub fn invoke(
+        &mut self,
+        invocation_id: InvocationId,
+        method_path: &str,
+        method_input: Vec<u8>,
+        ioid: IoId,
+    ) -> Result<(), AbiError> {
         let io_memory = self.io_memory.clone();
 
+        // the path may end in a version requirement, e.g. `akkoro.aws.s3.get_object@^0.2`
+        let (method_path, version) = match method_path.split_once('@') {
+            Some((_, "")) => return Err(AbiError::MalformedMethodPath),
+            Some((method_path, version)) => (method_path, Some(version.to_string())),
+            None => (method_path, None),
+        };
         let coords = method_path.split(".").collect::<Vec<&str>>();
-        if coords.len() != 4 {
-            panic!("Malformed method path @ Threader::invoke") // TODO don't panic
+        if coords.len() != 4 || coords.iter().any(|c| c.is_empty()) {
+            return Err(AbiError::MalformedMethodPath);
         }
 
         let iomod_coords = format!("{}.{}.{}", coords[0], coords[1], coords[2]);
-        let method_name = format!("{}", coords[3]);
+        let method_name = coords[3].to_string();
+        if !is_permitted(&iomod_coords, &method_name) {
+            return Err(AbiError::PermissionDenied);
+        }
+        self.admit(invocation_id, &iomod_coords)?;
 
+        let timeout = IOMOD_TIMEOUTS.get(&iomod_coords).copied();
+        let version = version.or_else(|| IOMOD_VERSIONS.get(&iomod_coords).cloned());
         let registry_tx = self.registry_tx.clone();
         let (local_tx, mut local_rx) = mpsc::channel(100);
 
-        let hnd = self.runtime.handle().clone();
-        hnd.spawn(async move {
-            tokio::spawn(async move {
-                registry_tx
+        let call = self.runtime.spawn(async move {
+            let coords = iomod_coords.clone();
+            let call = async {
+                // if the registry is gone the responder is dropped, and the call fails below
+                let _ = registry_tx
                     .send(RegistryChannelMessage {
                         iomod_coords,
                         method_name,
+                        version,
                         payload_type: "IOMOD_REQUEST",
                         payload: method_input,
-                        responder: Some(local_tx.clone()),
+                        error: None,
+                        responder: Some(local_tx),
                     })
-                    .await
-                    .unwrap();
-            });
-
-            tokio::spawn(async move {
-                if let Some(response) = local_rx.recv().await {
-                    io_memory
-                        .lock()
-                        .unwrap()
-                        .handle_response(response.payload, ioid);
+                    .await;
+                local_rx.recv().await
+            };
+            // the timeout is on the IOmod beginning to respond, so that a streamed response is
+            // not cut off once it is under way
+            let mut response = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, call).await.map_err(|_| {
+                    IoError::new(
+                        IoErrorKind::Timeout,
+                        format!(
+                            "{} did not respond within {}ms",
+                            coords,
+                            timeout.as_millis()
+                        ),
+                    )
+                }),
+                None => Ok(call.await),
+            };
+            loop {
+                match response {
+                    Ok(Some(chunk)) if chunk.payload_type == "IOMOD_CHUNK" => {
+                        match lock_io_memory(&io_memory).get_mut(&invocation_id) {
+                            Some(memory) => memory.append_chunk(ioid, chunk.payload),
+                            None => return,
+                        }
+                        response = Ok(local_rx.recv().await);
+                    }
+                    response => {
+                        // the invocation may have ended while the call was in-flight
+                        if let Some(memory) = lock_io_memory(&io_memory).get_mut(&invocation_id) {
+                            memory.handle_response(response.and_then(call_result), ioid);
+                        }
+                        return;
+                    }
                 }
-            });
+            }
         });
+        if let Some(memory) = lock_io_memory(&self.io_memory).get_mut(&invocation_id) {
+            memory.calls.insert(ioid, call);
+        }
+
+        Ok(())
+    }
+
+    /// Counts of the calls made so far, and of those rejected by the IO limits
+    pub fn io_metrics(&self) -> IoMetrics {
+        self.metrics.clone()
+    }
+
+    /// Check a new call to the IOmod at `iomod_coords` against the IO limits, counting it if it
+    /// is admitted or counting the limit it exceeded if not
+    fn admit(&mut self, invocation_id: InvocationId, iomod_coords: &str) -> Result<(), AbiError> {
+        let mut io_memory = lock_io_memory(&self.io_memory);
+        let memory = io_memory.get_mut(&invocation_id).ok_or(AbiError::Unknown)?;
+
+        if matches!(self.limits.calls, Some(max) if memory.call_count >= max) {
+            self.metrics.quota_exceeded += 1;
+            warn!(
+                invocation_id,
+                iomod = iomod_coords,
+                total = self.metrics.quota_exceeded,
+                "IOmod call rejected: invocation exceeded its call quota"
+            );
+            return Err(AbiError::CallQuotaExceeded);
+        }
+        // the IOID of the new call has been allocated, so it is already counted as pending
+        if matches!(self.limits.in_flight, Some(max) if memory.in_flight() > max as usize) {
+            self.metrics.in_flight_exceeded += 1;
+            warn!(
+                invocation_id,
+                iomod = iomod_coords,
+                total = self.metrics.in_flight_exceeded,
+                "IOmod call rejected: invocation has too many calls in-flight"
+            );
+            return Err(AbiError::InFlightLimitExceeded);
+        }
+        if let Some(rate) = self.limits.rates.get(iomod_coords) {
+            let limiter = self
+                .rate_limiters
+                .entry(iomod_coords.to_string())
+                .or_insert_with(|| RateLimiter::new(*rate));
+            if !limiter.try_acquire() {
+                let rate_limited = self
+                    .metrics
+                    .rate_limited
+                    .entry(iomod_coords.to_string())
+                    .or_default();
+                *rate_limited += 1;
+                warn!(
+                    invocation_id,
+                    iomod = iomod_coords,
+                    total = *rate_limited,
+                    "IOmod call rejected: rate limit exceeded"
+                );
+                return Err(AbiError::RateLimited);
+            }
+        }
+
+        memory.call_count += 1;
+        self.metrics.calls += 1;
+        Ok(())
     }
 
     /// Spawn a Future on the Threader tokio runtime
@@ -147,15 +379,6 @@ where
         let hnd = self.runtime.handle();
         hnd.spawn(future);
     }
-
-    /// Clear the IO memory.
-    /// This should NOT be called while any calls are still in-flight.
-    /// Intended for use preparing the environment for a subsequent handler execution.
-    pub fn __reset_memory(&self) {
-        if let Ok(mut memory) = self.io_memory.clone().lock() {
-            memory.reset();
-        }
-    }
 }
 
 #[derive(Clone)]
@@ -170,8 +393,19 @@ pub struct IoMemoryDocument {
 struct IoMemory {
     next_id: IoId,
     buffer: IoBuffer,
+    bytes_held: usize,
     document_map: HashMap<IoId, IoMemoryDocument>,
-    io_status: HashMap<IoId, bool>,
+    io_status: HashMap<IoId, CallStatus>,
+    calls: HashMap<IoId, JoinHandle<()>>,
+    /// The number of calls the invocation has made
+    call_count: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum CallStatus {
+    Pending,
+    Ready,
+    Failed,
 }
 
 impl IoMemory {
@@ -179,41 +413,188 @@ impl IoMemory {
         IoMemory {
             next_id: 1, // id 0 is reserved (null)
             buffer: IoBuffer::new(),
+            bytes_held: 0,
             document_map: Default::default(),
             io_status: Default::default(),
+            calls: Default::default(),
+            call_count: 0,
         }
     }
 
-    fn reset(&mut self) {
-        self.next_id = 1;
-        self.buffer = IoBuffer::new();
-        self.document_map.clear();
-        self.io_status.clear();
+    /// The number of calls which have not completed
+    fn in_flight(&self) -> usize {
+        self.io_status
+            .values()
+            .filter(|status| **status == CallStatus::Pending)
+            .count()
     }
 
     fn next_id(&mut self) -> Option<IoId> {
-        let next_id = self.next_id.clone();
+        let next_id = self.next_id;
         self.next_id += 1;
-        self.io_status.insert(next_id, false);
+        self.io_status.insert(next_id, CallStatus::Pending);
         Some(next_id)
     }
 
-    fn poll(&self, ioid: IoId) -> bool {
+    fn is_pending(&self, ioid: IoId) -> bool {
+        self.io_status.get(&ioid) == Some(&CallStatus::Pending)
+    }
+
+    fn poll(&self, ioid: IoId) -> Result<bool, AbiError> {
         match self.io_status.get(&ioid) {
-            Some(status) => *status,
-            None => false,
+            Some(status) => Ok(*status != CallStatus::Pending),
+            None => Err(AbiError::UnknownIoid),
+        }
+    }
+
+    fn failed(&self, ioid: IoId) -> Result<bool, AbiError> {
+        match self.io_status.get(&ioid) {
+            Some(CallStatus::Pending) => Err(AbiError::NotReady),
+            Some(status) => Ok(*status == CallStatus::Failed),
+            None => Err(AbiError::UnknownIoid),
+        }
+    }
+
+    fn cancel(&mut self, ioid: IoId) -> bool {
+        let exists = self.io_status.contains_key(&ioid);
+        self.free(ioid);
+        exists
+    }
+
+    fn free(&mut self, ioid: IoId) -> bool {
+        // a call may be freed while it is still streaming its response, or before it has
+        // responded at all
+        if let Some(call) = self.calls.remove(&ioid) {
+            call.abort();
+        }
+        let exists = self.io_status.remove(&ioid).is_some();
+        match self.document_map.remove(&ioid) {
+            Some(_) => {
+                self.bytes_held -= self.buffer.free(ioid as usize);
+                true
+            }
+            None => exists,
         }
     }
 
-    fn handle_response(&mut self, response: Vec<u8>, ioid: IoId) {
-        self.buffer.set(ioid as usize, response.clone());
-        self.io_status.insert(ioid, true);
-        self.document_map.insert(
-            ioid,
-            IoMemoryDocument {
+    /// Append a chunk of the response to `ioid` to its document
+    fn append_chunk(&mut self, ioid: IoId, chunk: Vec<u8>) {
+        // the call may have been cancelled or freed while it was in-flight
+        if !self.is_pending(ioid) {
+            return;
+        }
+        let length = self.buffer.append(ioid as usize, chunk);
+        self.bytes_held += length;
+        self.document_map
+            .entry(ioid)
+            .or_insert(IoMemoryDocument {
                 start: ioid as usize,
-                length: response.len(),
-            },
-        );
+                length: 0,
+            })
+            .length += length;
+    }
+
+    fn handle_response(&mut self, response: Result<Vec<u8>, IoError>, ioid: IoId) {
+        if !self.is_pending(ioid) {
+            return;
+        }
+        self.calls.remove(&ioid);
+        match response {
+            Ok(payload) => {
+                self.append_chunk(ioid, payload);
+                self.io_status.insert(ioid, CallStatus::Ready);
+            }
+            Err(error) => {
+                // the error replaces any of the response which was streamed before the call failed
+                self.bytes_held -= self.buffer.free(ioid as usize);
+                // unwrap: IoError always serializes
+                let error = serde_json::to_vec(&error).unwrap();
+                let length = self.buffer.set(ioid as usize, error);
+                self.bytes_held += length;
+                self.io_status.insert(ioid, CallStatus::Failed);
+                self.document_map.insert(
+                    ioid,
+                    IoMemoryDocument {
+                        start: ioid as usize,
+                        length,
+                    },
+                );
+            }
+        }
     }
 }
+
+impl Drop for IoMemory {
+    fn drop(&mut self) {
+        for call in self.calls.values() {
+            call.abort();
+        }
+    }
+}
+
+/// Map the response received from the registry to the outcome of a call
+fn call_result(response: Option<RegistryChannelMessage>) -> Result<Vec<u8>, IoError> {
+    match response {
+        Some(RegistryChannelMessage {
+            error: Some(error), ..
+        }) => Err(error),
+        Some(response) => Ok(response.payload),
+        None => Err(IoError::new(
+            IoErrorKind::Unavailable,
+            "the IOmod registry did not respond",
+        )),
+    }
+}
+
+/// Lock the IO memory of every invocation. A panic while it was held leaves at worst a
+/// document which is never freed, so a poisoned lock is recovered rather than propagated.
+fn lock_io_memory(
+    io_memory: &Mutex<HashMap<InvocationId, IoMemory>>,
+) -> MutexGuard<'_, HashMap<InvocationId, IoMemory>> {
+    io_memory.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Check the call to `method_name` at `iomod_coords` against `IOMOD_CAPABILITIES`
+fn is_permitted(iomod_coords: &str, method_name: &str) -> bool {
+    match IOMOD_CAPABILITIES.as_ref() {
+        Some(capabilities) => {
+            capabilities.contains(iomod_coords)
+                || capabilities.contains(&format!("{}.{}", iomod_coords, method_name))
+        }
+        None => true,
+    }
+}
+
+/// The entries of `IOMOD_CAPABILITIES` naming a method of `iomod_coords` which is not one of
+/// `methods`, the methods declared by the IOmod's manifest. Calls permitted by these entries
+/// can never succeed.
+pub fn undeclared_capabilities(iomod_coords: &str, methods: &[String]) -> Vec<String> {
+    let capabilities = match IOMOD_CAPABILITIES.as_ref() {
+        Some(capabilities) => capabilities,
+        None => return Vec::new(),
+    };
+    let prefix = format!("{}.", iomod_coords);
+    let mut undeclared = capabilities
+        .iter()
+        .filter(|capability| match capability.strip_prefix(&prefix) {
+            Some(method) => !methods.iter().any(|declared| declared == method),
+            None => false,
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    undeclared.sort();
+    undeclared
+}
+
+/// Parse per-IOmod settings of the form `org.namespace.name=value`, separated by commas, from
+/// the environment variable `var`
+pub(crate) fn parse_iomod_settings(var: &str) -> HashMap<String, String> {
+    let settings = std::env::var(var).unwrap_or_default();
+    settings
+        .split(',')
+        .filter_map(|entry| {
+            let (coords, value) = entry.split_once('=')?;
+            Some((coords.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
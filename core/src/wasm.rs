@@ -1,21 +1,29 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
 use std::iter::FromIterator;
-use std::mem::ManuallyDrop;
 use std::path::{Path, PathBuf};
 use std::string::ToString;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use std::{fmt, thread};
 
 use anyhow::anyhow;
 use once_cell::sync::Lazy;
-use wasmtime::{Caller, Config, Engine, Func, Instance, Linker, Module, Store};
+use wasmtime::{
+    Caller, Config, Engine, Func, Instance, InstanceAllocationStrategy, InstancePre, Linker,
+    Module, PoolingAllocationConfig, Store, Trap,
+};
 use wasmtime_wasi::{Dir, WasiCtx, WasiCtxBuilder};
 
+use assemblylift_core_io_common::abi::AbiError;
 use assemblylift_core_iomod::registry::RegistryTx;
 
 use crate::abi::*;
 use crate::buffers::FunctionInputBuffer;
-use crate::threader::Threader;
+use crate::limits::{FunctionLimiter, FunctionLimits, FUNCTION_LIMITS};
+use crate::threader::{InvocationId, IoMetrics, Threader};
 
 pub type BufferElement = (usize, u8);
 
@@ -24,13 +32,84 @@ pub type State<S> = AsmlFunctionState<S>;
 pub static CPU_COMPAT_MODE: Lazy<String> =
     Lazy::new(|| std::env::var("ASML_CPU_COMPAT_MODE").unwrap_or("default".to_string()));
 
+/// The function execution deadline, set from `timeout_seconds` in the service manifest
+pub static FUNCTION_TIMEOUT: Lazy<Option<Duration>> = Lazy::new(|| {
+    std::env::var("ASML_FUNCTION_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .map(Duration::from_secs)
+});
+
+/// The number of instance slots to reserve up-front for concurrent invocations. When set, the
+/// engine uses the pooling allocator rather than allocating instances on demand.
+pub static INSTANCE_POOL_SIZE: Lazy<Option<u32>> = Lazy::new(|| {
+    std::env::var("ASML_INSTANCE_POOL_SIZE")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .filter(|size| *size > 0)
+});
+
+/// Size of a WASM page in bytes
+const WASM_PAGE_SIZE: usize = 65536;
+/// Memory size in pages reserved by each pooled instance when no memory limit is set (4GiB)
+const POOLED_MEMORY_PAGES: u64 = 65536;
+/// Table size reserved by each pooled instance when no table limit is set
+const POOLED_TABLE_ELEMENTS: u32 = 100_000;
+
+/// Interval at which the engine epoch is incremented
+const EPOCH_TICK: Duration = Duration::from_millis(100);
+/// Epoch deadline used when a function has no timeout; large enough to never elapse,
+/// small enough not to overflow when added to the current epoch
+pub(crate) const EPOCH_DEADLINE_NONE: u64 = u64::MAX / 2;
+
+#[derive(Debug)]
+/// Errors raised by the execution of a WASM function which a runtime should report distinctly
+pub enum ExecutionError {
+    /// The function did not complete before its deadline
+    Timeout(Duration),
+    /// The function tried to grow a linear memory past its limit in bytes
+    MemoryLimitExceeded(usize),
+    /// The function tried to grow a table past its limit in elements
+    TableLimitExceeded(u32),
+}
+
+impl fmt::Display for ExecutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionError::Timeout(timeout) => write!(
+                f,
+                "timeout: function exceeded its deadline of {}ms",
+                timeout.as_millis()
+            ),
+            ExecutionError::MemoryLimitExceeded(limit) => write!(
+                f,
+                "memory limit exceeded: function may not use more than {} bytes of memory",
+                limit
+            ),
+            ExecutionError::TableLimitExceeded(limit) => write!(
+                f,
+                "table limit exceeded: function tables may not grow past {} elements",
+                limit
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExecutionError {}
+
 pub struct Wasmtime<R, S>
 where
     R: RuntimeAbi<S> + 'static,
     S: Clone + Send + Sized + 'static,
 {
     engine: Engine,
+    _epoch_ticker: EpochTicker,
     module: Module,
+    linker: Linker<State<S>>,
+    instance_pre: Option<InstancePre<State<S>>>,
+    threader: Arc<Mutex<Threader<S>>>,
+    timeout: Option<Duration>,
+    limits: FunctionLimits,
     _phantom_r: std::marker::PhantomData<R>,
     _phantom_s: std::marker::PhantomData<S>,
 }
@@ -40,15 +119,17 @@ where
     R: RuntimeAbi<S> + 'static,
     S: Clone + Send + Sized + 'static,
 {
-    pub fn new_from_path(module_path: &Path) -> anyhow::Result<Self> {
+    /// Load the module at `module_path`. IOmod calls made by the module are sent to the registry
+    /// at `registry_tx`.
+    pub fn new_from_path(module_path: &Path, registry_tx: RegistryTx) -> anyhow::Result<Self> {
         let m = match module_path.extension().unwrap().to_str().unwrap() {
             "bin" => {
-                let engine = new_engine(Some("x86_64-linux-gnu"), None)?;
+                let engine = new_engine(Some("x86_64-linux-gnu"), None, *INSTANCE_POOL_SIZE)?;
                 let module = unsafe { Module::deserialize_file(&engine, module_path) };
                 (engine, module)
             },
             "wasm" => {
-                let engine = new_engine(None, None)?;
+                let engine = new_engine(None, None, *INSTANCE_POOL_SIZE)?;
                 let module = Module::from_file(&engine, module_path);
                 (engine, module)
             },
@@ -57,180 +138,93 @@ where
             )),
         };
         match m.1 {
-            Ok(module) => Ok(Self {
-                engine: m.0,
-                module,
-                _phantom_r: Default::default(),
-                _phantom_s: Default::default(),
-            }),
+            Ok(module) => Self::new(m.0, module, registry_tx),
             Err(err) => Err(anyhow!(err)),
         }
     }
 
-    pub fn new_from_bytes(module_bytes: &[u8]) -> anyhow::Result<Self> {
-        let engine = new_engine(Some("x86_64-linux-gnu"), None)?;
+    pub fn new_from_bytes(module_bytes: &[u8], registry_tx: RegistryTx) -> anyhow::Result<Self> {
+        let engine = new_engine(Some("x86_64-linux-gnu"), None, *INSTANCE_POOL_SIZE)?;
         match unsafe { Module::deserialize(&engine, module_bytes) } {
-            Ok(module) => Ok(Self {
-                engine,
-                module,
-                _phantom_r: Default::default(),
-                _phantom_s: Default::default(),
-            }),
+            Ok(module) => Self::new(engine, module, registry_tx),
             Err(err) => Err(anyhow!(err)),
         }
     }
 
+    fn new(engine: Engine, module: Module, registry_tx: RegistryTx) -> anyhow::Result<Self> {
+        let linker = new_linker::<R, S>(&engine)?;
+        Ok(Self {
+            _epoch_ticker: EpochTicker::spawn(&engine),
+            engine,
+            module,
+            linker,
+            instance_pre: None,
+            threader: Arc::new(Mutex::new(Threader::new(registry_tx))),
+            timeout: *FUNCTION_TIMEOUT,
+            limits: FUNCTION_LIMITS.clone(),
+            _phantom_r: Default::default(),
+            _phantom_s: Default::default(),
+        })
+    }
+
+    /// Create a new store for an invocation and instantiate the module into it. The module is
+    /// linked against the host ABI once, on the first call; later calls only pay for instantiation.
+    /// The IO memory of the invocation is released when the returned store is dropped.
     pub fn link_module(
         &mut self,
-        registry_tx: RegistryTx,
         status_sender: crossbeam_channel::Sender<S>,
     ) -> anyhow::Result<(Instance, Store<State<S>>)> {
-        let threader = ManuallyDrop::new(Arc::new(Mutex::new(Threader::new(registry_tx))));
-        let mut linker: Linker<State<S>> = Linker::new(&self.engine);
-
-        // FIXME this might be confusingly named (confused with the function env vars)
-        let function_env = std::env::var("ASML_FUNCTION_ENV").unwrap_or("default".into());
-
-        if let Err(err) = wasmtime_wasi::add_to_linker(&mut linker, |s| &mut s.wasi) {
-            return Err(anyhow!(err));
-        }
-        // env vars prefixed with __ASML_ are defined in the function definition;
-        // the prefix indicates that they are to be mapped to the module environment
-        let envs: Vec<(String, String)> = Vec::from_iter(
-            std::env::vars()
-                .into_iter()
-                .filter(|e| e.0.starts_with("__ASML_"))
-                .map(|e| (e.0.replace("__ASML_", ""), e.1))
-                .into_iter(),
-        );
-        let wasi = match function_env.as_str() {
-            "ruby-docker" => WasiCtxBuilder::new()
-                .arg("/src/handler.rb")
-                .unwrap()
-                .env("RUBY_PLATFORM", "wasm32-wasi")
-                .unwrap()
-                .envs(&*envs)
-                .unwrap()
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/usr/bin/ruby-wasm32-wasi/src").unwrap()),
-                    "/src",
-                )
-                .expect("could not map guest dir -- is the image built correctly?")
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/usr/bin/ruby-wasm32-wasi/usr").unwrap()),
-                    "/usr",
-                )
-                .expect("could not map guest dir -- is the image built correctly?")
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
-                    "/tmp",
-                )
-                .expect("could not map guest dir -- is the image built correctly?")
-                .build(),
-            "ruby-lambda" => WasiCtxBuilder::new()
-                .arg("/src/handler.rb")
-                .unwrap()
-                .env("RUBY_PLATFORM", "wasm32-wasi")
-                .unwrap()
-                .envs(&*envs)
-                .unwrap()
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/tmp/rubysrc").unwrap()),
-                    "/src",
-                )
-                .expect("could not map guest dir -- is the image built correctly?")
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/tmp/rubyusr").unwrap()),
-                    "/usr",
-                )
-                .expect("could not map guest dir -- is the image built correctly?")
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
-                    "/tmp",
-                )
-                .expect("could not map guest tmpfs -- is /tmp accessible?")
-                .build(),
-            _ => WasiCtxBuilder::new()
-                .envs(&*envs)
-                .unwrap()
-                .preopened_dir(
-                    Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
-                    "/tmp",
-                )
-                .expect("could not map guest tmpfs -- is /tmp accessible?")
-                .build(),
-        };
+        let threader = self.threader.clone();
+        let invocation_id = threader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .begin_invocation();
+        let wasi = new_wasi_ctx();
 
         let state = State {
             function_input_buffer: FunctionInputBuffer::new(),
             status_sender,
             threader,
+            invocation_id,
             wasi,
+            limiter: FunctionLimiter::new(self.limits.clone()),
             io_buffer_ptr: None,
             function_input_buffer_ptr: None,
         };
         let mut store = Store::new(&self.engine, state);
+        store.limiter(|s| &mut s.limiter);
+        store.epoch_deadline_trap();
+        // the deadline is armed in `start`; until then (e.g. during instantiation) it must not elapse
+        store.set_epoch_deadline(EPOCH_DEADLINE_NONE);
 
-        linker
-            .func_wrap("env", "__asml_abi_runtime_log", R::log)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_runtime_success", R::success)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_invoke", asml_abi_io_invoke::<R, S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_io_invoke", asml_abi_io_invoke::<R, S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_io_poll", asml_abi_io_poll::<S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_io_len", asml_abi_io_len::<S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_io_load", asml_abi_io_load::<S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_io_next", asml_abi_io_next::<S>)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_clock_time_get", asml_abi_clock_time_get)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_input_start", asml_abi_input_start)
-            .unwrap();
-        linker
-            .func_wrap("env", "__asml_abi_input_next", asml_abi_input_next)
-            .unwrap();
-        linker
-            .func_wrap(
-                "env",
-                "__asml_abi_input_length_get",
-                asml_abi_input_length_get,
-            )
-            .unwrap();
+        let instance_pre = match &self.instance_pre {
+            Some(instance_pre) => instance_pre.clone(),
+            None => {
+                let instance_pre = self.linker.instantiate_pre(&mut store, &self.module)?;
+                self.instance_pre = Some(instance_pre.clone());
+                instance_pre
+            }
+        };
 
-        match linker.instantiate(&mut store, &self.module) {
+        match instance_pre.instantiate(&mut store) {
             Ok(instance) => {
-                let get_ptr = instance
-                    .get_export(&mut store, "__asml_guest_get_io_buffer_pointer")
-                    .unwrap()
-                    .into_func()
-                    .unwrap();
+                // a module missing the guest buffer exports fails to link, rather than panicking
+                let get_ptr = *instance
+                    .get_typed_func::<(), i32>(&mut store, "__asml_guest_get_io_buffer_pointer")?
+                    .func();
                 store.data_mut().io_buffer_ptr = Some(get_ptr);
 
-                let get_ptr = instance
-                    .get_export(&mut store, "__asml_guest_get_function_input_buffer_pointer")
-                    .unwrap()
-                    .into_func()
-                    .unwrap();
+                let get_ptr = *instance
+                    .get_typed_func::<(), i32>(
+                        &mut store,
+                        "__asml_guest_get_function_input_buffer_pointer",
+                    )?
+                    .func();
                 store.data_mut().function_input_buffer_ptr = Some(get_ptr);
 
                 Ok((instance, store))
             }
-            Err(err) => Err(anyhow!(err)),
+            Err(err) => Err(Self::execution_error(&store, err, None)),
         }
     }
 
@@ -243,61 +237,174 @@ where
         Ok(())
     }
 
+    /// Set the execution deadline applied by subsequent calls to `start`, overriding
+    /// the default read from `ASML_FUNCTION_TIMEOUT_SECONDS`
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Set the resource limits applied to stores created by subsequent calls to `link_module`,
+    /// overriding the defaults read from the environment. If the engine uses the pooling
+    /// allocator, the limits may not exceed those its instance slots were sized for.
+    pub fn set_limits(&mut self, limits: FunctionLimits) -> anyhow::Result<()> {
+        if INSTANCE_POOL_SIZE.is_some() {
+            check_pooled_limits(&limits)?;
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// Run the module's `_start` function. If the function runs past its deadline or exceeds
+    /// its resource limits it traps, and the error returned can be downcast to `ExecutionError`.
     pub fn start(
         &mut self,
         mut store: &mut Store<State<S>>,
         instance: Instance,
     ) -> anyhow::Result<()> {
-        match instance
-            .get_func(&mut store, "_start")
-            .expect("could not find default function")
-            .typed::<(), ()>(&mut store)
-            .expect("invalid default function signature")
-            .call(&mut store, ())
-        {
+        store.set_epoch_deadline(match self.timeout {
+            Some(timeout) => deadline_ticks(timeout),
+            None => EPOCH_DEADLINE_NONE,
+        });
+        let start = instance
+            .get_typed_func::<(), ()>(&mut store, "_start")
+            .map_err(|err| anyhow!("invalid default function: {}", err))?;
+        match start.call(&mut store, ()) {
             Ok(_) => Ok(()),
-            Err(trap) => Err(trap.into()),
+            Err(err) => Err(Self::execution_error(store, err, self.timeout)),
+        }
+    }
+
+    /// Map a trap raised while instantiating or running a function to an `ExecutionError` where
+    /// its cause is known
+    fn execution_error(
+        store: &Store<State<S>>,
+        err: anyhow::Error,
+        timeout: Option<Duration>,
+    ) -> anyhow::Error {
+        let limiter = &store.data().limiter;
+        if let (Some(Trap::Interrupt), Some(timeout)) = (err.downcast_ref::<Trap>(), timeout) {
+            anyhow!(ExecutionError::Timeout(timeout))
+        } else if limiter.memory_limit_exceeded() {
+            // unwrap: the limit is only exceeded if it is set
+            anyhow!(ExecutionError::MemoryLimitExceeded(
+                limiter.limits().memory_bytes.unwrap()
+            ))
+        } else if limiter.table_limit_exceeded() {
+            // unwrap: the limit is only exceeded if it is set
+            anyhow!(ExecutionError::TableLimitExceeded(
+                limiter.limits().table_elements.unwrap()
+            ))
+        } else {
+            err
         }
     }
 
+    /// The number of bytes of IO memory held by each in-flight invocation
+    pub fn io_memory_usage(&self) -> HashMap<InvocationId, usize> {
+        self.threader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .io_memory_usage()
+    }
+
+    /// Counts of the IOmod calls made by all invocations, and of those rejected by the IO limits
+    pub fn io_metrics(&self) -> IoMetrics {
+        self.threader
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .io_metrics()
+    }
+
+    /// Read a UTF-8 string of `len` bytes at `ptr` from guest memory
     pub fn ptr_to_string(
         caller: &mut Caller<'_, State<S>>,
         ptr: u32,
         len: u32,
-    ) -> anyhow::Result<String> {
-        let bytes = Self::ptr_to_bytes(caller, ptr, len).unwrap();
-        let s = std::str::from_utf8(&bytes).unwrap();
-        Ok(s.into())
+    ) -> Result<String, AbiError> {
+        let bytes = Self::ptr_to_bytes(caller, ptr, len)?;
+        String::from_utf8(bytes).map_err(|_| AbiError::InvalidUtf8)
     }
 
+    /// Read `len` bytes at `ptr` from guest memory
     pub fn ptr_to_bytes(
         caller: &mut Caller<'_, State<S>>,
         ptr: u32,
         len: u32,
-    ) -> anyhow::Result<Vec<u8>> {
-        let memory = caller
-            .get_export("memory")
-            .expect("could not find the default memory export named \"memory\"")
-            .into_memory()
-            .unwrap();
-        let mut buffer: Vec<u8> = vec![0; len as usize];
-        match memory.read(&caller, ptr as usize, &mut buffer) {
-            Ok(_) => Ok(buffer),
-            Err(err) => Err(err.into()),
-        }
+    ) -> Result<Vec<u8>, AbiError> {
+        read_memory(caller, ptr, len)
+    }
+}
+
+/// Read `len` bytes at `ptr` from the memory exported by the guest of `caller`
+pub(crate) fn read_memory<T>(
+    caller: &mut Caller<'_, T>,
+    ptr: u32,
+    len: u32,
+) -> Result<Vec<u8>, AbiError> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or(AbiError::MissingExport)?;
+    // check bounds before allocating, so that a bad length cannot exhaust host memory
+    match (ptr as usize).checked_add(len as usize) {
+        Some(end) if end <= memory.data_size(&*caller) => {}
+        _ => return Err(AbiError::InvalidPointer),
+    }
+    let mut buffer: Vec<u8> = vec![0; len as usize];
+    match memory.read(caller, ptr as usize, &mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(_err) => Err(AbiError::InvalidPointer),
     }
 }
 
+/// Lock a `Wasmtime` shared between invocations. A panic during one invocation leaves it usable
+/// by the next, so a poisoned lock is recovered rather than failing every later invocation.
+pub fn lock_wasmtime<R, S>(wasmtime: &Mutex<Wasmtime<R, S>>) -> MutexGuard<'_, Wasmtime<R, S>>
+where
+    R: RuntimeAbi<S> + 'static,
+    S: Clone + Send + Sized + 'static,
+{
+    wasmtime.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 pub struct AsmlFunctionState<S>
 where
     S: Clone + Send + Sized + 'static,
 {
     pub function_input_buffer: FunctionInputBuffer,
     pub status_sender: crossbeam_channel::Sender<S>,
-    pub threader: ManuallyDrop<Arc<Mutex<Threader<S>>>>,
+    pub threader: Arc<Mutex<Threader<S>>>,
+    pub invocation_id: InvocationId,
     pub io_buffer_ptr: Option<Func>,
     pub function_input_buffer_ptr: Option<Func>,
     wasi: WasiCtx,
+    limiter: FunctionLimiter,
+}
+
+impl<S> AsmlFunctionState<S>
+where
+    S: Clone + Send + Sized + 'static,
+{
+    /// The number of bytes of IO memory held by this invocation, i.e. IOmod responses which
+    /// the guest has not yet freed
+    pub fn io_memory_held(&self) -> usize {
+        self.lock_threader().io_memory_held(self.invocation_id)
+    }
+
+    /// Lock the Threader for a host call. The Threader is left consistent by a panic in a
+    /// host call, so a poisoned lock is recovered rather than failing every later call.
+    pub fn lock_threader(&self) -> MutexGuard<'_, Threader<S>> {
+        self.threader.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<S> Drop for AsmlFunctionState<S>
+where
+    S: Clone + Send + Sized + 'static,
+{
+    fn drop(&mut self) {
+        self.lock_threader().end_invocation(self.invocation_id);
+    }
 }
 
 pub fn precompile(module_path: &Path, target: &str, mode: &str) -> anyhow::Result<PathBuf> {
@@ -308,7 +415,7 @@ pub fn precompile(module_path: &Path, target: &str, mode: &str) -> anyhow::Resul
         Ok(bytes) => bytes,
         Err(err) => return Err(err.into()),
     };
-    let engine = new_engine(Some(target), Some(mode))?;
+    let engine = new_engine(Some(target), Some(mode), None)?;
     let compiled_bytes = engine
         .precompile_module(&*wasm_bytes)
         .expect("TODO: panic message");
@@ -322,7 +429,148 @@ pub fn precompile(module_path: &Path, target: &str, mode: &str) -> anyhow::Resul
     Ok(PathBuf::from(file_path))
 }
 
-fn new_engine(target: Option<&str>, cpu_compat_mode: Option<&str>) -> anyhow::Result<Engine> {
+/// Create a `Linker` providing WASI and the AssemblyLift ABI
+fn new_linker<R, S>(engine: &Engine) -> anyhow::Result<Linker<State<S>>>
+where
+    R: RuntimeAbi<S> + 'static,
+    S: Clone + Send + Sized + 'static,
+{
+    let mut linker: Linker<State<S>> = Linker::new(engine);
+
+    if let Err(err) = wasmtime_wasi::add_to_linker(&mut linker, |s| &mut s.wasi) {
+        return Err(anyhow!(err));
+    }
+
+    linker
+        .func_wrap("env", "__asml_abi_runtime_log", R::log)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_runtime_success", R::success)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_invoke", asml_abi_io_invoke::<R, S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_invoke", asml_abi_io_invoke::<R, S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_poll", asml_abi_io_poll::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_status", asml_abi_io_status::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_len", asml_abi_io_len::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_load", asml_abi_io_load::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_next", asml_abi_io_next::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_free", asml_abi_io_free::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_io_cancel", asml_abi_io_cancel::<S>)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_clock_time_get", asml_abi_clock_time_get)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_input_start", asml_abi_input_start)
+        .unwrap();
+    linker
+        .func_wrap("env", "__asml_abi_input_next", asml_abi_input_next)
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "__asml_abi_input_length_get",
+            asml_abi_input_length_get,
+        )
+        .unwrap();
+
+    Ok(linker)
+}
+
+fn new_wasi_ctx() -> WasiCtx {
+    // FIXME this might be confusingly named (confused with the function env vars)
+    let function_env = std::env::var("ASML_FUNCTION_ENV").unwrap_or("default".into());
+
+    // env vars prefixed with __ASML_ are defined in the function definition;
+    // the prefix indicates that they are to be mapped to the module environment
+    let envs: Vec<(String, String)> = Vec::from_iter(
+        std::env::vars()
+            .into_iter()
+            .filter(|e| e.0.starts_with("__ASML_"))
+            .map(|e| (e.0.replace("__ASML_", ""), e.1))
+            .into_iter(),
+    );
+    match function_env.as_str() {
+        "ruby-docker" => WasiCtxBuilder::new()
+            .arg("/src/handler.rb")
+            .unwrap()
+            .env("RUBY_PLATFORM", "wasm32-wasi")
+            .unwrap()
+            .envs(&*envs)
+            .unwrap()
+            .preopened_dir(
+                Dir::from_std_file(File::open("/usr/bin/ruby-wasm32-wasi/src").unwrap()),
+                "/src",
+            )
+            .expect("could not map guest dir -- is the image built correctly?")
+            .preopened_dir(
+                Dir::from_std_file(File::open("/usr/bin/ruby-wasm32-wasi/usr").unwrap()),
+                "/usr",
+            )
+            .expect("could not map guest dir -- is the image built correctly?")
+            .preopened_dir(
+                Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
+                "/tmp",
+            )
+            .expect("could not map guest dir -- is the image built correctly?")
+            .build(),
+        "ruby-lambda" => WasiCtxBuilder::new()
+            .arg("/src/handler.rb")
+            .unwrap()
+            .env("RUBY_PLATFORM", "wasm32-wasi")
+            .unwrap()
+            .envs(&*envs)
+            .unwrap()
+            .preopened_dir(
+                Dir::from_std_file(File::open("/tmp/rubysrc").unwrap()),
+                "/src",
+            )
+            .expect("could not map guest dir -- is the image built correctly?")
+            .preopened_dir(
+                Dir::from_std_file(File::open("/tmp/rubyusr").unwrap()),
+                "/usr",
+            )
+            .expect("could not map guest dir -- is the image built correctly?")
+            .preopened_dir(
+                Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
+                "/tmp",
+            )
+            .expect("could not map guest tmpfs -- is /tmp accessible?")
+            .build(),
+        _ => WasiCtxBuilder::new()
+            .envs(&*envs)
+            .unwrap()
+            .preopened_dir(
+                Dir::from_std_file(File::open("/tmp/asmltmp").unwrap()),
+                "/tmp",
+            )
+            .expect("could not map guest tmpfs -- is /tmp accessible?")
+            .build(),
+    }
+}
+
+fn new_engine(
+    target: Option<&str>,
+    cpu_compat_mode: Option<&str>,
+    pool_size: Option<u32>,
+) -> anyhow::Result<Engine> {
     let mode = match cpu_compat_mode {
         Some(mode) => mode,
         None => CPU_COMPAT_MODE.as_str(),
@@ -349,6 +597,10 @@ fn new_engine(target: Option<&str>, cpu_compat_mode: Option<&str>) -> anyhow::Re
         },
         _ => Config::new().clone(),
     };
+    config.epoch_interruption(true);
+    if let Some(pool_size) = pool_size {
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling_config(pool_size)));
+    }
     let config = match target {
         Some(target) => config.target(target).unwrap().clone(),
         None => config,
@@ -358,3 +610,85 @@ fn new_engine(target: Option<&str>, cpu_compat_mode: Option<&str>) -> anyhow::Re
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Size the instance pool to hold `pool_size` function instances within `FUNCTION_LIMITS`
+fn pooling_config(pool_size: u32) -> PoolingAllocationConfig {
+    let mut config = PoolingAllocationConfig::default();
+    config
+        .instance_count(pool_size)
+        .instance_memories(FUNCTION_LIMITS.memories as u32)
+        .instance_tables(FUNCTION_LIMITS.tables as u32)
+        .instance_table_elements(pooled_table_elements(&FUNCTION_LIMITS))
+        .instance_memory_pages(pooled_memory_pages(&FUNCTION_LIMITS));
+    config
+}
+
+/// Memory size in pages reserved by each pooled instance sized for `limits`
+fn pooled_memory_pages(limits: &FunctionLimits) -> u64 {
+    limits
+        .memory_bytes
+        .map(|bytes| (bytes / WASM_PAGE_SIZE) as u64)
+        .unwrap_or(POOLED_MEMORY_PAGES)
+}
+
+/// Table size reserved by each pooled instance sized for `limits`
+fn pooled_table_elements(limits: &FunctionLimits) -> u32 {
+    limits.table_elements.unwrap_or(POOLED_TABLE_ELEMENTS)
+}
+
+/// Check that `limits` fit within the instance slots of the pool, which are sized for
+/// `FUNCTION_LIMITS` when the engine is built
+fn check_pooled_limits(limits: &FunctionLimits) -> anyhow::Result<()> {
+    let pooled = &*FUNCTION_LIMITS;
+    if pooled_memory_pages(limits) > pooled_memory_pages(pooled) {
+        return Err(anyhow!(
+            "memory limit exceeds the {} pages reserved by each pooled instance",
+            pooled_memory_pages(pooled)
+        ));
+    }
+    if pooled_table_elements(limits) > pooled_table_elements(pooled) {
+        return Err(anyhow!(
+            "table limit exceeds the {} elements reserved by each pooled instance",
+            pooled_table_elements(pooled)
+        ));
+    }
+    if limits.memories > pooled.memories || limits.tables > pooled.tables {
+        return Err(anyhow!(
+            "limits allow more memories or tables than each pooled instance reserves"
+        ));
+    }
+    Ok(())
+}
+
+/// Advances the epoch of an engine every `EPOCH_TICK`, driving execution deadlines, until dropped
+pub(crate) struct EpochTicker {
+    stop: Arc<AtomicBool>,
+}
+
+impl EpochTicker {
+    /// Spawn the thread ticking `engine`, which exits and releases `engine` once the ticker is
+    /// dropped
+    pub(crate) fn spawn(engine: &Engine) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let ticker_stop = stop.clone();
+        let ticker_engine = engine.clone();
+        thread::spawn(move || {
+            while !ticker_stop.load(Ordering::Relaxed) {
+                thread::sleep(EPOCH_TICK);
+                ticker_engine.increment_epoch();
+            }
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for EpochTicker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn deadline_ticks(timeout: Duration) -> u64 {
+    let ticks = timeout.as_millis() / EPOCH_TICK.as_millis();
+    std::cmp::max(ticks as u64, 1)
+}
@@ -0,0 +1,428 @@
+//! IOmods compiled to WebAssembly, which run inside the runtime's process.
+//!
+//! A WASM IOmod is loaded into a store of its own on a thread dedicated to it, and is registered
+//! with the registry as a local IOmod; calls reach it like calls to any other IOmod, and are
+//! served one at a time. The module has WASI, with the arguments, environment, and working
+//! directory of its manifest, and makes network requests through the host's HTTP client.
+//!
+//! A WASM IOmod is held to the same limits as a function. Its memory is capped at the
+//! `resources.memory_mb` of its manifest, or else at the function's `FunctionLimits`, and each
+//! call must complete within the `timeout_seconds` of the IOmod dependency (or
+//! `DEFAULT_CALL_TIMEOUT`). A call which exceeds either fails, and the IOmod is restarted in a
+//! fresh store.
+//!
+//! The module must export:
+//! - `memory`
+//! - `__asml_iomod_alloc(len: u32) -> u32`, returning a buffer of `len` bytes for the host
+//! - `__asml_iomod_invoke(method_ptr: u32, method_len: u32, input_ptr: u32, input_len: u32) -> i32`,
+//!   returning 0 if the call succeeded, -1 if there is no such method, or -2 if the call failed
+//!
+//! and may import from `env`:
+//! - `__asml_iomod_respond(ptr: u32, len: u32)`, which sets the response to the current call,
+//!   or its error message if the call fails
+//! - `__asml_iomod_http_request(ptr: u32, len: u32, out_ptr: u32, out_len: u32) -> i32`,
+//!   which performs the JSON `HttpRequest` at `ptr` and writes the pointer and length of a
+//!   JSON `HttpResponse` (or of an error message, if it returns -1) at `out_ptr` and `out_len`
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use wasmtime::{Caller, Config, Engine, Instance, Linker, Module, Store, Trap, TypedFunc};
+use wasmtime_wasi::{Dir, WasiCtx, WasiCtxBuilder};
+
+use assemblylift_core_iomod::supervisor::IomodProcess;
+use assemblylift_core_iomod::{CallRequest, CallResponse, IoError, IoErrorKind};
+
+use crate::limits::{FunctionLimiter, FunctionLimits, FUNCTION_LIMITS};
+use crate::threader::IOMOD_TIMEOUTS;
+use crate::wasm::{deadline_ticks, read_memory, EpochTicker, EPOCH_DEADLINE_NONE};
+
+/// The number of calls which may be queued for a WASM IOmod
+const CALL_QUEUE_SIZE: usize = 32;
+/// How long a call may run if the IOmod dependency does not set `timeout_seconds`
+const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The engine shared by every WASM IOmod, whose epoch drives the deadline of each call. The
+/// engine lives as long as the process, and so does its ticker.
+static ENGINE: Lazy<anyhow::Result<(Engine, EpochTicker)>> = Lazy::new(|| {
+    let mut config = Config::new();
+    config.epoch_interruption(true);
+    let engine = Engine::new(&config)?;
+    let ticker = EpochTicker::spawn(&engine);
+    Ok((engine, ticker))
+});
+
+type InvokeFunc = TypedFunc<(u32, u32, u32, u32), i32>;
+
+/// An HTTP request made by a WASM IOmod
+#[derive(Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Vec<u8>,
+}
+
+/// The response to an `HttpRequest`
+#[derive(Serialize)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+struct WasmIomodState {
+    wasi: WasiCtx,
+    http: reqwest::blocking::Client,
+    limiter: FunctionLimiter,
+    /// The response set by the IOmod for the call it is serving
+    response: Vec<u8>,
+}
+
+/// A WASM IOmod, whose calls are served by a thread of its own
+pub struct WasmIomod {
+    coordinates: String,
+    version: String,
+    tx: mpsc::Sender<CallRequest>,
+}
+
+impl WasmIomod {
+    /// Load the module at the entrypoint of `process`, and start the thread serving its calls
+    pub fn spawn(process: &IomodProcess) -> anyhow::Result<Self> {
+        let (engine, _) = ENGINE.as_ref().map_err(|why| anyhow!("{}", why))?;
+        let module = Module::from_file(engine, &process.entrypoint)?;
+        let linker = new_linker(engine)?;
+        // the WASI context is built here so that a bad manifest fails the spawn
+        let wasi = new_wasi_ctx(process)?;
+        let sandbox = Sandbox {
+            limits: match process.resources.as_ref().and_then(|r| r.memory_mb) {
+                Some(memory_mb) => FunctionLimits::from_size_mb(memory_mb as usize),
+                None => FUNCTION_LIMITS.clone(),
+            },
+            timeout: IOMOD_TIMEOUTS
+                .get(&process.coordinates)
+                .copied()
+                .unwrap_or(DEFAULT_CALL_TIMEOUT),
+        };
+        let label = process.label();
+
+        let (tx, rx) = mpsc::channel(CALL_QUEUE_SIZE);
+        // the store is created on the IOmod's thread, so that the HTTP client is too
+        let thread_process = process.clone();
+        std::thread::Builder::new()
+            .name(label.clone())
+            .spawn(move || {
+                let iomod = Served {
+                    process: thread_process,
+                    sandbox,
+                    linker,
+                    module,
+                    http: reqwest::blocking::Client::new(),
+                };
+                serve(&iomod, wasi, rx);
+            })?;
+        info!("loaded WASM IOmod {}", label);
+
+        Ok(Self {
+            coordinates: process.coordinates.clone(),
+            version: process.version.clone(),
+            tx,
+        })
+    }
+
+    pub fn coordinates(&self) -> &str {
+        &self.coordinates
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The sender of calls to the IOmod, with which it is registered
+    pub fn sender(&self) -> mpsc::Sender<CallRequest> {
+        self.tx.clone()
+    }
+}
+
+/// The limits a WASM IOmod runs within
+struct Sandbox {
+    limits: FunctionLimits,
+    /// How long each call, and the IOmod's initialization, may run
+    timeout: Duration,
+}
+
+/// Everything the thread of a WASM IOmod needs to (re)start it
+struct Served {
+    process: IomodProcess,
+    sandbox: Sandbox,
+    linker: Linker<WasmIomodState>,
+    module: Module,
+    http: reqwest::blocking::Client,
+}
+
+impl Served {
+    /// Instantiate the IOmod in a new store
+    fn instantiate(
+        &self,
+        wasi: WasiCtx,
+    ) -> anyhow::Result<(Store<WasmIomodState>, Instance, InvokeFunc)> {
+        let state = WasmIomodState {
+            wasi,
+            http: self.http.clone(),
+            limiter: FunctionLimiter::new(self.sandbox.limits.clone()),
+            response: Vec::new(),
+        };
+        let mut store = Store::new(self.module.engine(), state);
+        store.limiter(|s| &mut s.limiter);
+        store.epoch_deadline_trap();
+        store.set_epoch_deadline(deadline_ticks(self.sandbox.timeout));
+
+        let instance = self.linker.instantiate(&mut store, &self.module)?;
+        // a reactor module initializes itself before its exports are called
+        if let Ok(initialize) = instance.get_typed_func::<(), ()>(&mut store, "_initialize") {
+            initialize.call(&mut store, ())?;
+        }
+        let invoke = instance.get_typed_func(&mut store, "__asml_iomod_invoke")?;
+        store.set_epoch_deadline(EPOCH_DEADLINE_NONE);
+        Ok((store, instance, invoke))
+    }
+
+    /// The error for a call during which the IOmod trapped
+    fn trap_error(&self, store: &Store<WasmIomodState>, why: &anyhow::Error) -> IoError {
+        let label = self.process.label();
+        let limiter = &store.data().limiter;
+        if let Some(Trap::Interrupt) = why.downcast_ref::<Trap>() {
+            IoError::new(
+                IoErrorKind::Timeout,
+                format!(
+                    "IOmod {} did not complete the call within {}ms",
+                    label,
+                    self.sandbox.timeout.as_millis()
+                ),
+            )
+        } else if limiter.memory_limit_exceeded() || limiter.table_limit_exceeded() {
+            IoError::new(
+                IoErrorKind::Failed,
+                format!("IOmod {} exceeded its resource limits: {}", label, why),
+            )
+        } else {
+            IoError::new(
+                IoErrorKind::Failed,
+                format!("IOmod {} trapped: {}", label, why),
+            )
+        }
+    }
+}
+
+/// Serve each call received on `rx`, until every sender has been dropped
+fn serve(iomod: &Served, wasi: WasiCtx, mut rx: mpsc::Receiver<CallRequest>) {
+    let label = iomod.process.label();
+    let (mut store, mut instance, mut invoke) = match iomod.instantiate(wasi) {
+        Ok(instantiated) => instantiated,
+        Err(why) => {
+            error!("could not instantiate IOmod {}: {}", label, why);
+            return;
+        }
+    };
+
+    while let Some(request) = rx.blocking_recv() {
+        store.set_epoch_deadline(deadline_ticks(iomod.sandbox.timeout));
+        let result = call(&mut store, &instance, &invoke, &request.coords, &request.input);
+        store.set_epoch_deadline(EPOCH_DEADLINE_NONE);
+        let (payload, error) = match result {
+            Ok(payload) => (payload, None),
+            Err(CallError::Io(error)) => (Vec::new(), Some(error)),
+            Err(CallError::Trap(why)) => {
+                let error = iomod.trap_error(&store, &why);
+                error!("{}", error.message);
+                // the instance may have been left in any state, so start over with a new one
+                let restarted = new_wasi_ctx(&iomod.process)
+                    .and_then(|wasi| iomod.instantiate(wasi));
+                match restarted {
+                    Ok((new_store, new_instance, new_invoke)) => {
+                        store = new_store;
+                        instance = new_instance;
+                        invoke = new_invoke;
+                    }
+                    Err(why) => {
+                        error!("could not restart IOmod {}: {}", label, why);
+                        return;
+                    }
+                }
+                (Vec::new(), Some(error))
+            }
+        };
+        let _ = request.responder.blocking_send(CallResponse {
+            coords: request.coords,
+            payload,
+            error,
+            partial: false,
+        });
+    }
+}
+
+enum CallError {
+    /// The IOmod reported that the call failed
+    Io(IoError),
+    /// The IOmod trapped while serving the call
+    Trap(anyhow::Error),
+}
+
+/// Invoke `method` on the IOmod with `input`
+fn call(
+    store: &mut Store<WasmIomodState>,
+    instance: &Instance,
+    invoke: &InvokeFunc,
+    method: &str,
+    input: &[u8],
+) -> Result<Vec<u8>, CallError> {
+    let method_ptr = write_guest(store, instance, method.as_bytes()).map_err(CallError::Trap)?;
+    let input_ptr = write_guest(store, instance, input).map_err(CallError::Trap)?;
+
+    store.data_mut().response.clear();
+    let status = invoke
+        .call(
+            &mut *store,
+            (method_ptr, method.len() as u32, input_ptr, input.len() as u32),
+        )
+        .map_err(CallError::Trap)?;
+    let response = std::mem::take(&mut store.data_mut().response);
+    match status {
+        0 => Ok(response),
+        -1 => Err(CallError::Io(IoError::new(
+            IoErrorKind::MethodNotFound,
+            format!("no method {}", method),
+        ))),
+        _ => Err(CallError::Io(IoError::new(
+            IoErrorKind::Failed,
+            String::from_utf8_lossy(&response),
+        ))),
+    }
+}
+
+/// Copy `bytes` into a buffer allocated by the IOmod, returning the buffer's pointer
+fn write_guest(
+    store: &mut Store<WasmIomodState>,
+    instance: &Instance,
+    bytes: &[u8],
+) -> anyhow::Result<u32> {
+    let alloc = instance.get_typed_func::<u32, u32>(&mut *store, "__asml_iomod_alloc")?;
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .ok_or_else(|| anyhow!("IOmod does not export memory"))?;
+    let ptr = alloc.call(&mut *store, bytes.len() as u32)?;
+    memory.write(&mut *store, ptr as usize, bytes)?;
+    Ok(ptr)
+}
+
+/// Create a `Linker` providing WASI and the IOmod host functions
+fn new_linker(engine: &Engine) -> anyhow::Result<Linker<WasmIomodState>> {
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::add_to_linker(&mut linker, |s: &mut WasmIomodState| &mut s.wasi)?;
+    linker.func_wrap("env", "__asml_iomod_respond", iomod_respond)?;
+    linker.func_wrap("env", "__asml_iomod_http_request", iomod_http_request)?;
+    Ok(linker)
+}
+
+fn new_wasi_ctx(process: &IomodProcess) -> anyhow::Result<WasiCtx> {
+    let mut builder = WasiCtxBuilder::new()
+        .inherit_stdout()
+        .inherit_stderr()
+        .arg(&process.label())?
+        .args(&process.arguments)?;
+    for (key, value) in &process.environment {
+        builder = builder.env(key, value)?;
+    }
+    if let Some(working_dir) = &process.working_dir {
+        builder = builder.preopened_dir(Dir::from_std_file(File::open(working_dir)?), ".")?;
+    }
+    Ok(builder.build())
+}
+
+fn iomod_respond(mut caller: Caller<'_, WasmIomodState>, ptr: u32, len: u32) {
+    match read_guest(&mut caller, ptr, len) {
+        Ok(response) => caller.data_mut().response = response,
+        Err(why) => error!("IOmod passed an invalid response: {}", why),
+    }
+}
+
+fn iomod_http_request(
+    mut caller: Caller<'_, WasmIomodState>,
+    ptr: u32,
+    len: u32,
+    out_ptr: u32,
+    out_len: u32,
+) -> i32 {
+    let (status, output) = match read_guest(&mut caller, ptr, len)
+        .and_then(|request| http_request(&caller.data().http, &request))
+    {
+        Ok(response) => (0, response),
+        Err(why) => (-1, why.to_string().into_bytes()),
+    };
+    match write_output(&mut caller, &output, out_ptr, out_len) {
+        Ok(_) => status,
+        Err(why) => {
+            error!("could not return HTTP response to IOmod: {}", why);
+            -1
+        }
+    }
+}
+
+/// Perform the JSON `HttpRequest` in `request`, returning a JSON `HttpResponse`
+fn http_request(http: &reqwest::blocking::Client, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let request: HttpRequest = serde_json::from_slice(request)?;
+    let method = reqwest::Method::from_bytes(request.method.to_uppercase().as_bytes())?;
+    let mut builder = http.request(method, &request.url).body(request.body);
+    for (key, value) in &request.headers {
+        builder = builder.header(key, value);
+    }
+    let response = builder.send()?;
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+        .collect();
+    let body = response.bytes()?.to_vec();
+    Ok(serde_json::to_vec(&HttpResponse {
+        status,
+        headers,
+        body,
+    })?)
+}
+
+fn read_guest(caller: &mut Caller<'_, WasmIomodState>, ptr: u32, len: u32) -> anyhow::Result<Vec<u8>> {
+    Ok(read_memory(caller, ptr, len)?)
+}
+
+/// Copy `output` into a buffer allocated by the IOmod, and write its pointer and length to
+/// `out_ptr` and `out_len`
+fn write_output(
+    caller: &mut Caller<'_, WasmIomodState>,
+    output: &[u8],
+    out_ptr: u32,
+    out_len: u32,
+) -> anyhow::Result<()> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| anyhow!("IOmod does not export memory"))?;
+    let alloc = caller
+        .get_export("__asml_iomod_alloc")
+        .and_then(|export| export.into_func())
+        .ok_or_else(|| anyhow!("IOmod does not export __asml_iomod_alloc"))?
+        .typed::<u32, u32>(&*caller)?;
+    let ptr = alloc.call(&mut *caller, output.len() as u32)?;
+    memory.write(&mut *caller, ptr as usize, output)?;
+    memory.write(&mut *caller, out_ptr as usize, &ptr.to_le_bytes())?;
+    memory.write(&mut *caller, out_len as usize, &(output.len() as u32).to_le_bytes())?;
+    Ok(())
+}
@@ -8,16 +8,22 @@ pub struct LambdaAbi;
 
 impl RuntimeAbi<Status> for LambdaAbi {
     fn log(mut caller: Caller<'_, State<Status>>, ptr: u32, len: u32) {
-        let s = Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len).unwrap();
-        println!("LOG: {}", s);
+        match Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len) {
+            Ok(s) => println!("LOG: {}", s),
+            Err(err) => println!("ERROR: could not read guest log message: {}", err),
+        }
     }
 
     fn success(mut caller: Caller<'_, State<Status>>, ptr: u32, len: u32) {
         let lambda_runtime = &crate::LAMBDA_RUNTIME;
-        let response = Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len).unwrap();
-
-        let respond = lambda_runtime.respond(response);
-        let state = caller.data_mut();
-        state.threader.clone().lock().unwrap().spawn(respond);
+        let response = Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len);
+        let threader = caller.data().lock_threader();
+        match response {
+            Ok(response) => threader.spawn(lambda_runtime.respond(response)),
+            Err(err) => threader.spawn(lambda_runtime.error(
+                "Function.Error",
+                format!("could not read function response: {}", err),
+            )),
+        }
     }
 }
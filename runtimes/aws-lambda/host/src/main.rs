@@ -5,17 +5,25 @@ use std::fs::File;
 use std::io::{BufReader, Read};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use std::process;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::crate_version;
 use crossbeam_channel::bounded;
 use once_cell::sync::Lazy;
 use tokio::sync::mpsc;
+use tracing::{debug, warn, Level};
+use tracing_subscriber::FmtSubscriber;
 use zip;
 
-use assemblylift_core::wasm::Wasmtime;
-use assemblylift_core_iomod::{package::IomodManifest, registry};
+use assemblylift_core::limits::FunctionLimits;
+use assemblylift_core::threader;
+use assemblylift_core::wasm::{lock_wasmtime, ExecutionError, Wasmtime};
+use assemblylift_core::wasm_iomod::WasmIomod;
+use assemblylift_core_iomod::package::{self, IomodManifest, ProcessKind};
+use assemblylift_core_iomod::registry::{self, RegistryConfig};
+use assemblylift_core_iomod::supervisor::{self, IomodProcess, IomodSupervisor};
+use assemblylift_core_iomod::transport::RegistryAddress;
 use runtime::AwsLambdaRuntime;
 
 use crate::abi::LambdaAbi;
@@ -24,6 +32,12 @@ mod abi;
 mod runtime;
 
 pub static LAMBDA_RUNTIME: Lazy<AwsLambdaRuntime> = Lazy::new(|| AwsLambdaRuntime::new());
+/// Time reserved at the end of an invocation to report a timeout before Lambda stops the runtime
+const DEADLINE_MARGIN_MS: u64 = 500;
+/// The registry socket, unless `ASML_REGISTRY_ADDRESS` is set; the IOmods run alongside the
+/// runtime, so the registry need not listen on the network
+const REGISTRY_SOCKET: &str = "/tmp/asml-registry.sock";
+
 pub static LAMBDA_REQUEST_ID: Lazy<Mutex<RefCell<String>>> =
     Lazy::new(|| Mutex::new(RefCell::new(String::new())));
 
@@ -34,16 +48,30 @@ async fn main() {
         crate_version!()
     );
 
+    // the registry and IOmod supervisor log with tracing, which includes the IOmods' own output
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::INFO)
+        .without_time()
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
+
     let registry_channel = mpsc::channel(32);
     let tx = registry_channel.0.clone();
     let rx = registry_channel.1;
-    registry::spawn_registry(rx).unwrap();
+    let mut registry_config = RegistryConfig::from_env().unwrap();
+    if env::var("ASML_REGISTRY_ADDRESS").is_err() {
+        registry_config.address = RegistryAddress::Unix(PathBuf::from(REGISTRY_SOCKET));
+    }
+    let iomod_environment = registry_config.iomod_environment();
+    let registry = registry::spawn_registry_with(rx, registry_config).unwrap();
+    let mut readiness = registry.readiness();
 
     // load plugins from runtime dir, which should contain merged contents of Lambda layers
+    let mut iomods = Vec::new();
     if let Ok(rd) = fs::read_dir("/opt") {
         for entry in rd {
             let entry = entry.unwrap();
-            println!("DEBUG entry={:?}", entry);
+            debug!("entry={:?}", entry);
             if entry.file_type().unwrap().is_file() {
                 // FIXME this makes the assumption that the
                 //       IOmod entrypoint is always an executable binary
@@ -63,40 +91,45 @@ async fn main() {
                                     .read_to_string(&mut manifest_str)
                                     .expect("could not read iomod.toml");
                             }
-                            {
-                                let mut archive = archive.borrow_mut();
-                                let iomod_manifest = IomodManifest::from(manifest_str);
-                                let entrypoint = format!("./{}", iomod_manifest.process.entrypoint);
-                                let mut entrypoint_binary = archive
-                                    .by_name(&*entrypoint)
-                                    .expect("could not find entrypoint in package");
-                                let path = &*format!(
-                                    "/tmp/iomod/{}@{}/{}",
-                                    iomod_manifest.iomod.coordinates,
-                                    iomod_manifest.iomod.version,
-                                    entrypoint
-                                );
-                                let path = Path::new(path);
-                                if !path.exists() {
-                                    {
-                                        let path_prefix = path.parent().unwrap();
-                                        fs::create_dir_all(path_prefix).expect(&*format!(
-                                            "unable to create directory {:?}",
-                                            path_prefix
-                                        ));
-                                        let mut entrypoint_file = File::create(path)
-                                            .expect(&*format!("unable to create file at {:?}", path));
-                                        std::io::copy(&mut entrypoint_binary, &mut entrypoint_file)
-                                            .expect("unable to copy entrypoint");
-                                        let mut perms: fs::Permissions =
-                                            fs::metadata(&path).unwrap().permissions();
-                                        perms.set_mode(0o755);
-                                        entrypoint_file.set_permissions(perms)
-                                            .expect("could not set IOmod binary executable (octal 755) permissions");
-                                    }
-                                    process::Command::new(path).spawn().unwrap();
+                            let iomod_manifest = match IomodManifest::parse(&manifest_str)
+                                .and_then(|manifest| {
+                                    manifest.validate()?;
+                                    manifest.check_core_version(package::CORE_VERSION)?;
+                                    Ok(manifest)
+                                }) {
+                                Ok(manifest) => manifest,
+                                Err(why) => {
+                                    println!("ERROR skipping IOmod {:?}: {}", entry.path(), why);
+                                    continue;
+                                }
+                            };
+                            let iomod_dir = PathBuf::from(format!(
+                                "/tmp/iomod/{}@{}",
+                                iomod_manifest.iomod.coordinates,
+                                iomod_manifest.iomod.version
+                            ));
+                            if !iomod_dir.exists() {
+                                // the whole package is unpacked, as the IOmod may run from a
+                                // working_dir within it
+                                archive
+                                    .borrow_mut()
+                                    .extract(&iomod_dir)
+                                    .expect(&*format!("unable to unpack IOmod to {:?}", iomod_dir));
+                                let path = iomod_dir.join(&iomod_manifest.process.entrypoint);
+                                let mut perms: fs::Permissions = fs::metadata(&path)
+                                    .expect("could not find entrypoint in package")
+                                    .permissions();
+                                // a WASM entrypoint is loaded by the runtime, not executed
+                                if iomod_manifest.process.kind == ProcessKind::Native {
+                                    perms.set_mode(0o755);
+                                    fs::set_permissions(&path, perms)
+                                        .expect("could not set IOmod binary executable (octal 755) permissions");
                                 }
                             }
+                            let mut iomod =
+                                IomodProcess::from_manifest(&iomod_manifest, &iomod_dir);
+                            iomod.environment.extend(iomod_environment.clone());
+                            iomods.push(iomod);
                         }
                     }
                 }
@@ -105,6 +138,40 @@ async fn main() {
     } else {
         println!("WARN Could not find dir /opt/iomod");
     }
+    for iomod in iomods.iter() {
+        if let Some(methods) = &iomod.methods {
+            for capability in threader::undeclared_capabilities(&iomod.coordinates, methods) {
+                warn!("capability {} is not a method of IOmod {}", capability, iomod.label());
+            }
+        }
+    }
+    // WASM IOmods run in this process, and are registered directly
+    let (wasm_iomods, iomods): (Vec<_>, Vec<_>) = iomods
+        .into_iter()
+        .partition(|iomod| iomod.kind == ProcessKind::Wasm);
+    let mut _wasm_iomods = Vec::new();
+    for iomod in wasm_iomods {
+        match WasmIomod::spawn(&iomod) {
+            Ok(wasm_iomod) => {
+                if let Err(why) = registry.register_local(
+                    wasm_iomod.coordinates(),
+                    wasm_iomod.version(),
+                    wasm_iomod.sender(),
+                ) {
+                    println!("ERROR could not register IOmod {}: {}", iomod.label(), why);
+                }
+                _wasm_iomods.push(wasm_iomod);
+            }
+            Err(why) => println!("ERROR could not load IOmod {}: {}", iomod.label(), why),
+        }
+    }
+    // the IOmods are stopped when the runtime is sent SIGTERM or SIGINT
+    let mut supervisor = IomodSupervisor::spawn(iomods);
+
+    // IOmods register asynchronously, so wait for them before taking the first event
+    if let Err(why) = readiness.wait().await {
+        println!("WARN {}", why);
+    }
 
     let module_path = env::var("LAMBDA_TASK_ROOT").unwrap();
     let handler_name = env::var("_HANDLER").unwrap();
@@ -154,14 +221,26 @@ async fn main() {
 
     let (status_sender, _status_receiver) = bounded::<()>(1);
 
-    tokio::task::LocalSet::new().run_until(async move {
+    let local = tokio::task::LocalSet::new();
+    let event_loop = local.run_until(async move {
         let mut full_path = PathBuf::from(&module_path);
         full_path.push(&handler_name);
         let wasmtime = Arc::new(Mutex::new(
-            Wasmtime::<LambdaAbi, ()>::new_from_path(Path::new(full_path.as_path()))
+            Wasmtime::<LambdaAbi, ()>::new_from_path(Path::new(full_path.as_path()), tx)
                 .expect("could not create WASM runtime from module path")
         ));
 
+        // Lambda exposes the function's `size_mb` as its configured memory size
+        if let Some(size_mb) = env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+        {
+            let limits = FunctionLimits::from_size_mb(size_mb);
+            if let Err(why) = lock_wasmtime(&wasmtime).set_limits(limits) {
+                println!("ERROR could not cap guest memory at {}MB: {}", size_mb, why);
+            }
+        }
+
         while let Ok(event) = LAMBDA_RUNTIME.get_next_event().await {
             {
                 let ref_cell = LAMBDA_REQUEST_ID.lock().unwrap();
@@ -171,31 +250,79 @@ async fn main() {
                 ref_cell.replace(event.request_id.clone());
             }
 
-            let (instance, mut store) = wasmtime
-                .lock()
-                .unwrap()
-                .link_module(tx.clone(), status_sender.clone())
-                .expect("could not link wasm module");
+            if let Some(deadline_ms) = event.deadline_ms {
+                let now_ms = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time is broken")
+                    .as_millis() as u64;
+                let remaining_ms = deadline_ms
+                    .saturating_sub(now_ms)
+                    .saturating_sub(DEADLINE_MARGIN_MS);
+                lock_wasmtime(&wasmtime).set_timeout(Some(Duration::from_millis(remaining_ms)));
+            }
+
+            let link_result = lock_wasmtime(&wasmtime).link_module(status_sender.clone());
+            let (instance, mut store) = match link_result {
+                Ok(linked) => linked,
+                Err(error) => {
+                    println!("ERROR: could not link wasm module: {}", error.to_string());
+                    if let Err(why) = LAMBDA_RUNTIME.error("Function.Error", error.to_string()).await {
+                        println!("ERROR: could not report invocation error: {}", why);
+                    }
+                    continue;
+                }
+            };
 
-            wasmtime
-                .lock()
-                .unwrap()
+            lock_wasmtime(&wasmtime)
                 .initialize_function_input_buffer(&mut store, &event.event_body.into_bytes())
                 .expect("could not initialize input buffer");
 
             let wasmtime = wasmtime.clone();
             tokio::task::spawn_local(async move {
-                // env.clone().threader.lock().unwrap().__reset_memory();
-
-                match wasmtime.lock().unwrap().start(&mut store, instance) {
+                let result = lock_wasmtime(&wasmtime).start(&mut store, instance);
+                let held = store.data().io_memory_held();
+                if held > 0 {
+                    warn!(
+                        "invocation {} exited holding {} bytes of IO memory",
+                        store.data().invocation_id,
+                        held
+                    );
+                }
+                let metrics = lock_wasmtime(&wasmtime).io_metrics();
+                debug!(
+                    calls = metrics.calls,
+                    quota_exceeded = metrics.quota_exceeded,
+                    in_flight_exceeded = metrics.in_flight_exceeded,
+                    rate_limited = ?metrics.rate_limited,
+                    "IOmod calls"
+                );
+                match result {
                     Ok(result) => println!("SUCCESS: handler returned {:?}", result),
-                    Err(error) => println!("ERROR: {}", error.to_string()),
+                    Err(error) => {
+                        println!("ERROR: {}", error.to_string());
+                        let error_type = match error.downcast_ref::<ExecutionError>() {
+                            Some(ExecutionError::Timeout(_)) => "Function.Timeout",
+                            Some(ExecutionError::MemoryLimitExceeded(_))
+                            | Some(ExecutionError::TableLimitExceeded(_)) => {
+                                "Function.ResourceLimitExceeded"
+                            }
+                            None => "Function.Error",
+                        };
+                        if let Err(why) = LAMBDA_RUNTIME.error(error_type, error.to_string()).await {
+                            println!("ERROR: could not report invocation error: {}", why);
+                        }
+                    }
                 }
             })
             .await
             .unwrap();
-            // std::mem::drop(env.clone().threader);
         }
-    })
-    .await;
+    });
+
+    tokio::select! {
+        _ = event_loop => {}
+        _ = supervisor::shutdown_signal() => {}
+    }
+    println!("INFO stopping IOmods");
+    tokio::task::block_in_place(|| supervisor.shutdown());
 }
@@ -2,6 +2,7 @@ use std::env;
 use std::io::{Error, ErrorKind};
 
 use reqwest::Client;
+use serde_json::json;
 
 use crate::LAMBDA_REQUEST_ID;
 
@@ -10,6 +11,7 @@ use crate::LAMBDA_REQUEST_ID;
 #[derive(Debug)]
 pub struct AwsLambdaEvent {
     pub request_id: String,
+    pub deadline_ms: Option<u64>,
     pub event_body: String,
 }
 
@@ -45,10 +47,17 @@ impl AwsLambdaRuntime {
                     }
                 };
 
+                let deadline_ms = res
+                    .headers()
+                    .get("Lambda-Runtime-Deadline-Ms")
+                    .and_then(|deadline| deadline.to_str().ok())
+                    .and_then(|deadline| deadline.parse::<u64>().ok());
+
                 let event_body = res.text().await.unwrap();
 
                 Ok(AwsLambdaEvent {
                     request_id,
+                    deadline_ms,
                     event_body,
                 })
             }
@@ -74,4 +83,30 @@ impl AwsLambdaRuntime {
             Err(why) => Err(Error::new(ErrorKind::Other, why.to_string())),
         }
     }
+
+    pub async fn error(&self, error_type: &str, message: String) -> Result<(), Error> {
+        let request_id: String;
+        {
+            let ref_cell = LAMBDA_REQUEST_ID.lock().unwrap();
+            request_id = ref_cell.borrow().clone();
+        }
+        let url = &format!(
+            "http://{}/2018-06-01/runtime/invocation/{}/error",
+            self.api_endpoint, request_id
+        )
+        .to_string();
+        let body = json!({ "errorMessage": message, "errorType": error_type }).to_string();
+
+        match self
+            .client
+            .post(url)
+            .header("Lambda-Runtime-Function-Error-Type", error_type)
+            .body(body)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(why) => Err(Error::new(ErrorKind::Other, why.to_string())),
+        }
+    }
 }
@@ -10,16 +10,21 @@ pub struct GenericDockerAbi;
 
 impl RuntimeAbi<Status> for GenericDockerAbi {
     fn log(mut caller: Caller<'_, State<Status>>, ptr: u32, len: u32) {
-        let s = Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len).unwrap();
-        info!("Guest: {}", s);
+        match Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len) {
+            Ok(s) => info!("Guest: {}", s),
+            Err(err) => error!("could not read guest log message: {}", err),
+        }
     }
 
     fn success(mut caller: Caller<'_, State<Status>>, ptr: u32, len: u32) {
         debug!("called success");
         let tx = caller.data().status_sender.clone();
-        let s = Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len).unwrap();
+        let status = match Wasmtime::<Self, Status>::ptr_to_string(&mut caller, ptr, len) {
+            Ok(s) => Status::Success(s),
+            Err(err) => Status::Failure(format!("could not read function response: {}", err)),
+        };
         std::thread::spawn(move || {
-            if let Err(e) = tx.send(Status::Success(s.into())) {
+            if let Err(e) = tx.send(status) {
                 error!("could not send status: {:?}", e.to_string())
             }
         });
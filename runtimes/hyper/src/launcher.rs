@@ -6,10 +6,12 @@ use crossbeam_channel::bounded;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
+
+use assemblylift_core_iomod::registry::RegistryReadiness;
 
 use crate::Status::Exited;
-use crate::{Failure, RunnerMessage, RunnerTx, StatusRx, StatusTx, Success};
+use crate::{Failure, RunnerMessage, RunnerTx, StatusRx, StatusTx, Success, Timeout};
 
 pub struct Launcher {
     runtime: tokio::runtime::Runtime,
@@ -22,9 +24,14 @@ impl Launcher {
         }
     }
 
-    pub fn spawn(&mut self, runner_tx: RunnerTx) {
+    pub fn spawn(&mut self, runner_tx: RunnerTx, mut readiness: RegistryReadiness) {
         info!("Spawning launcher");
         tokio::task::LocalSet::new().block_on(&self.runtime, async {
+            // IOmods register asynchronously, so wait for them before serving requests
+            if let Err(why) = readiness.wait().await {
+                warn!("serving before all IOmods registered: {}", why);
+            }
+
             let make_svc = make_service_fn(|_| {
                 debug!("called make_service_fn");
                 let channel = bounded(32);
@@ -93,6 +100,10 @@ async fn launch(
                 .status(500)
                 .body(Body::from(response))
                 .unwrap(),
+            Timeout(response) => Response::builder()
+                .status(504)
+                .body(Body::from(response))
+                .unwrap(),
         });
     }
 
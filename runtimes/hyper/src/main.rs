@@ -1,18 +1,23 @@
 use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
 use clap::crate_version;
 use tokio::sync::mpsc;
-use tracing::{info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
+use assemblylift_core::threader;
 use assemblylift_core::wasm::Wasmtime;
-use assemblylift_core_iomod::registry;
+use assemblylift_core::wasm_iomod::WasmIomod;
+use assemblylift_core_iomod::package::ProcessKind;
+use assemblylift_core_iomod::registry::{self, RegistryConfig};
+use assemblylift_core_iomod::supervisor::{self, IomodProcess, IomodSupervisor};
 
 use crate::abi::GenericDockerAbi;
 use crate::launcher::Launcher;
 use crate::runner::{Runner, RunnerMessage, RunnerTx};
-use crate::Status::{Failure, Success};
+use crate::Status::{Failure, Success, Timeout};
 
 mod abi;
 mod launcher;
@@ -29,6 +34,7 @@ pub enum Status {
     Exited(i32),
     Success(String),
     Failure(String),
+    Timeout(String),
 }
 
 fn main() {
@@ -44,7 +50,48 @@ fn main() {
     fs::create_dir_all("/tmp/asmltmp").expect("could not create /tmp/asmltmp");
 
     let (registry_tx, registry_rx) = mpsc::channel(32);
-    registry::spawn_registry(registry_rx).unwrap();
+    let registry_config = RegistryConfig::from_env().unwrap();
+    let iomod_environment = registry_config.iomod_environment();
+    let registry = registry::spawn_registry_with(registry_rx, registry_config).unwrap();
+
+    // IOmods unpacked into the image are run alongside the function; the supervisor stops them
+    // when it is dropped as the runtime exits
+    let iomod_dir = std::env::var("ASML_IOMOD_DIR").unwrap_or("/opt/assemblylift/iomod".into());
+    let mut iomods = IomodProcess::discover(Path::new(&iomod_dir)).unwrap_or_else(|_| {
+        info!("No IOmods found at {}", iomod_dir);
+        Vec::new()
+    });
+    for iomod in iomods.iter_mut() {
+        iomod.environment.extend(iomod_environment.clone());
+    }
+    for iomod in iomods.iter() {
+        if let Some(methods) = &iomod.methods {
+            for capability in threader::undeclared_capabilities(&iomod.coordinates, methods) {
+                warn!("capability {} is not a method of IOmod {}", capability, iomod.label());
+            }
+        }
+    }
+    // WASM IOmods run in this process, and are registered directly
+    let (wasm_iomods, iomods): (Vec<_>, Vec<_>) = iomods
+        .into_iter()
+        .partition(|iomod| iomod.kind == ProcessKind::Wasm);
+    let mut _wasm_iomods = Vec::new();
+    for iomod in wasm_iomods {
+        match WasmIomod::spawn(&iomod) {
+            Ok(wasm_iomod) => {
+                if let Err(why) = registry.register_local(
+                    wasm_iomod.coordinates(),
+                    wasm_iomod.version(),
+                    wasm_iomod.sender(),
+                ) {
+                    error!("could not register IOmod {}: {}", iomod.label(), why);
+                }
+                _wasm_iomods.push(wasm_iomod);
+            }
+            Err(why) => error!("could not load IOmod {}: {}", iomod.label(), why),
+        }
+    }
+    let mut supervisor = IomodSupervisor::spawn(iomods);
 
     let wasmtime = Arc::new(Mutex::new(
         Wasmtime::<GenericDockerAbi, Status>::new_from_path(
@@ -53,12 +100,13 @@ fn main() {
                 std::env::var("ASML_WASM_MODULE_NAME").unwrap_or("handler.wasm.bin".into())
             )
             .as_ref(),
+            registry_tx,
         )
         .expect("could not create WASM runtime from module path"),
     ));
 
     crossbeam_utils::thread::scope(|s| {
-        let runner = Arc::new(Mutex::new(Runner::new(registry_tx, wasmtime)));
+        let runner = Arc::new(Mutex::new(Runner::new(wasmtime)));
         let tx = { runner.clone().lock().unwrap().sender() };
 
         let r = runner.clone();
@@ -68,7 +116,20 @@ fn main() {
 
         s.spawn(move |_| {
             let mut launcher = Launcher::new();
-            launcher.spawn(tx);
+            launcher.spawn(tx, registry.readiness());
+        });
+
+        // the runner and launcher never return, so the runtime exits once the IOmods have
+        // stopped
+        s.spawn(move |_| {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            rt.block_on(supervisor::shutdown_signal());
+            info!("Stopping IOmods");
+            supervisor.shutdown();
+            std::process::exit(0);
         });
     })
     .unwrap();
@@ -1,10 +1,9 @@
 use std::sync::{Arc, Mutex};
 
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
-use assemblylift_core::wasm::Wasmtime;
-use assemblylift_core_iomod::registry::RegistryTx;
+use assemblylift_core::wasm::{lock_wasmtime, ExecutionError, Wasmtime};
 
 use crate::{GenericDockerAbi, Status, StatusTx};
 
@@ -20,19 +19,14 @@ pub struct RunnerMessage {
 
 pub struct Runner {
     channel: RunnerChannel,
-    registry_tx: RegistryTx,
     runtime: tokio::runtime::Runtime,
     wasmtime: Arc<Mutex<Wasmtime<GenericDockerAbi, Status>>>,
 }
 
 impl Runner {
-    pub fn new(
-        registry_tx: RegistryTx,
-        wasmtime: Arc<Mutex<Wasmtime<GenericDockerAbi, Status>>>,
-    ) -> Self {
+    pub fn new(wasmtime: Arc<Mutex<Wasmtime<GenericDockerAbi, Status>>>) -> Self {
         Runner {
             channel: mpsc::channel(32),
-            registry_tx,
             runtime: tokio::runtime::Runtime::new().unwrap(),
             wasmtime,
         }
@@ -44,26 +38,53 @@ impl Runner {
             while let Some(msg) = self.channel.1.recv().await {
                 debug!("received runner message");
 
-                let (instance, mut store) = self
-                    .wasmtime
-                    .lock()
-                    .unwrap()
-                    .link_module(self.registry_tx.clone(), msg.status_sender.clone())
-                    .expect("could not link wasm module");
+                let link_result =
+                    lock_wasmtime(&self.wasmtime).link_module(msg.status_sender.clone());
+                let (instance, mut store) = match link_result {
+                    Ok(linked) => linked,
+                    Err(err) => {
+                        error!("could not link wasm module: {}", err);
+                        if let Err(e) = msg.status_sender.send(Status::Failure(err.to_string())) {
+                            error!("could not send status: {:?}", e.to_string())
+                        }
+                        continue;
+                    }
+                };
 
-                self.wasmtime
-                    .lock()
-                    .unwrap()
+                lock_wasmtime(&self.wasmtime)
                     .initialize_function_input_buffer(&mut store, &msg.input)
                     .expect("could not initialize input buffer");
 
                 let wasmtime = self.wasmtime.clone();
                 tokio::task::spawn_local(async move {
-                    match wasmtime.lock().unwrap().start(&mut store, instance) {
+                    let result = lock_wasmtime(&wasmtime).start(&mut store, instance);
+                    let held = store.data().io_memory_held();
+                    if held > 0 {
+                        warn!(
+                            "invocation {} exited holding {} bytes of IO memory",
+                            store.data().invocation_id,
+                            held
+                        );
+                    }
+                    let metrics = lock_wasmtime(&wasmtime).io_metrics();
+                    debug!(
+                        calls = metrics.calls,
+                        quota_exceeded = metrics.quota_exceeded,
+                        in_flight_exceeded = metrics.in_flight_exceeded,
+                        rate_limited = ?metrics.rate_limited,
+                        "IOmod calls"
+                    );
+                    match result {
                         Ok(_) => msg.status_sender.send(Status::Exited(0)),
-                        Err(_) => msg
-                            .status_sender
-                            .send(Status::Failure("WASM module exited in error".to_string())),
+                        Err(err) => match err.downcast_ref::<ExecutionError>() {
+                            Some(ExecutionError::Timeout(_)) => {
+                                msg.status_sender.send(Status::Timeout(err.to_string()))
+                            }
+                            Some(_) => msg.status_sender.send(Status::Failure(err.to_string())),
+                            None => msg
+                                .status_sender
+                                .send(Status::Failure("WASM module exited in error".to_string())),
+                        },
                     }
                 });
             }